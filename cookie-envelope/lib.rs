@@ -0,0 +1,364 @@
+// Seals the encrypted envelopes stored in typed cookies under content keys and opens them,
+// wraps content keys to X25519 keys of the contract's key registry for their owner and
+// grantees, and computes the keyed domain tags of blinded entries, so clients share one
+// implementation instead of rolling their own crypto.
+//
+// Each profile has a random content key, stored with `set_content_key` wrapped to its
+// owner. Each cookie's envelopes are sealed under a key derived from it and the cookie's
+// name, which stays the same across writes, so a profile grant opens every cookie of the
+// profile and a cookie grant keeps opening the cookie after it is patched.
+
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use scale::Encode;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret, StaticSecret};
+
+pub use cookie_contract::{
+    host_domains, normalize_domain, Algorithm, DomainHash, DomainRef, Envelope, ENVELOPE_VERSION,
+};
+
+/// Length of the symmetric content key used by both algorithms
+pub const KEY_LEN: usize = 32;
+
+/// Envelope version migrated from storage written before `ENVELOPE_VERSION` 2, opened with
+/// the content key it was sealed under
+pub const LEGACY_ENVELOPE_VERSION: u8 = 1;
+
+/// Context string binding keys agreed with a public key to this envelope format
+const KDF_INFO: &[u8] = b"cookiechain envelope content key";
+
+/// Context string binding cookie keys to the profile content key they derive from
+const COOKIE_KEY_INFO: &[u8] = b"cookiechain cookie key";
+
+/// Errors returned when sealing or opening an envelope
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    UnsupportedVersion,
+    InvalidNonce,
+    // A public key is of low order, so no secret could be agreed with it
+    InvalidKey,
+    // Encryption failed, or the ciphertext, header or key do not match
+    Crypto,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Error::UnsupportedVersion => write!(f, "unsupported envelope version"),
+            Error::InvalidNonce => write!(f, "nonce length does not match the algorithm"),
+            Error::InvalidKey => write!(f, "public key cannot be used for key agreement"),
+            Error::Crypto => write!(f, "envelope could not be sealed or opened"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+// X25519 public key of a secret, as registered with the contract's `set_public_key`
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+}
+
+// The header is authenticated as associated data so it cannot be swapped on chain
+fn header(version: u8, algorithm: Algorithm, key_id: u32, ephemeral_key: &[u8; 32]) -> Vec<u8> {
+    (version, algorithm, key_id, ephemeral_key).encode()
+}
+
+// Key of an envelope sealed to a public key, derived with HKDF-SHA256 from the X25519
+// shared secret and salted with both public keys
+fn derive_key(
+    shared: SharedSecret,
+    ephemeral_key: &[u8; 32],
+    recipient: &[u8; 32],
+) -> Result<[u8; KEY_LEN], Error> {
+    if !shared.was_contributory() {
+        return Err(Error::InvalidKey);
+    }
+    let salt = [&ephemeral_key[..], &recipient[..]].concat();
+    let mut key = [0; KEY_LEN];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(KDF_INFO, &mut key)
+        .map_err(|_| Error::Crypto)?;
+    Ok(key)
+}
+
+// Fresh random content key for a profile
+pub fn new_content_key() -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+// Key a cookie's envelopes are sealed under, derived with HKDF-SHA256 from the content key
+// of its profile
+pub fn cookie_key(profile_key: &[u8; KEY_LEN], cookie: &str) -> [u8; KEY_LEN] {
+    let mut key = [0; KEY_LEN];
+    Hkdf::<Sha256>::new(None, profile_key)
+        .expand_multi_info(&[COOKIE_KEY_INFO, cookie.as_bytes()], &mut key)
+        .expect("a key fits in one HKDF-SHA256 output");
+    key
+}
+
+// Encrypt `plaintext` under `key` with a fresh nonce
+fn encrypt(
+    algorithm: Algorithm,
+    key_id: u32,
+    ephemeral_key: [u8; 32],
+    key: &[u8; KEY_LEN],
+    plaintext: &[u8],
+) -> Result<Envelope, Error> {
+    let aad = header(ENVELOPE_VERSION, algorithm, key_id, &ephemeral_key);
+    let payload = Payload { msg: plaintext, aad: &aad };
+    let (nonce, ciphertext) = match algorithm {
+        Algorithm::XChaCha20Poly1305 => {
+            let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+            let ciphertext = XChaCha20Poly1305::new(key.into()).encrypt(&nonce, payload);
+            (nonce.to_vec(), ciphertext)
+        }
+        Algorithm::Aes256Gcm => {
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = Aes256Gcm::new(key.into()).encrypt(&nonce, payload);
+            (nonce.to_vec(), ciphertext)
+        }
+    };
+
+    Ok(Envelope {
+        version: ENVELOPE_VERSION,
+        algorithm,
+        key_id,
+        ephemeral_key,
+        nonce,
+        ciphertext: ciphertext.map_err(|_| Error::Crypto)?,
+    })
+}
+
+// Encrypt a cookie's payload under its `cookie_key`. `key_id` must be the owner's X25519 key
+// the profile's content key is wrapped to.
+pub fn seal(
+    algorithm: Algorithm,
+    key_id: u32,
+    key: &[u8; KEY_LEN],
+    plaintext: &[u8],
+) -> Result<Envelope, Error> {
+    encrypt(algorithm, key_id, [0; 32], key, plaintext)
+}
+
+// Encrypt `plaintext` to the X25519 public key `recipient` with a fresh ephemeral key and
+// nonce. `key_id` must be the id the contract assigned to `recipient` in the key registry.
+pub fn seal_to(
+    algorithm: Algorithm,
+    key_id: u32,
+    recipient: &[u8; 32],
+    plaintext: &[u8],
+) -> Result<Envelope, Error> {
+    let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+    let ephemeral_key = PublicKey::from(&ephemeral).to_bytes();
+    let shared = ephemeral.diffie_hellman(&PublicKey::from(*recipient));
+    let key = derive_key(shared, &ephemeral_key, recipient)?;
+    encrypt(algorithm, key_id, ephemeral_key, &key, plaintext)
+}
+
+// Decrypt an envelope sealed to the public key of `secret`
+pub fn open_with(envelope: &Envelope, secret: &[u8; 32]) -> Result<Vec<u8>, Error> {
+    let secret = StaticSecret::from(*secret);
+    let recipient = PublicKey::from(&secret).to_bytes();
+    let shared = secret.diffie_hellman(&PublicKey::from(envelope.ephemeral_key));
+    open(envelope, &derive_key(shared, &envelope.ephemeral_key, &recipient)?)
+}
+
+// Decrypt an envelope with the key it was sealed under, checking its tag against the
+// ciphertext and header. Grantees get the key from their grant's `wrapped_key`, or derive it
+// with `cookie_key` from a profile grant's.
+pub fn open(envelope: &Envelope, key: &[u8; KEY_LEN]) -> Result<Vec<u8>, Error> {
+    let aad = match envelope.version {
+        // Sealed before envelopes carried an ephemeral key, which the header left out
+        LEGACY_ENVELOPE_VERSION => {
+            (envelope.version, envelope.algorithm, envelope.key_id).encode()
+        }
+        ENVELOPE_VERSION => header(
+            envelope.version,
+            envelope.algorithm,
+            envelope.key_id,
+            &envelope.ephemeral_key,
+        ),
+        _ => return Err(Error::UnsupportedVersion),
+    };
+    if envelope.nonce.len() != envelope.algorithm.nonce_len() {
+        return Err(Error::InvalidNonce);
+    }
+
+    let payload = Payload { msg: &envelope.ciphertext, aad: &aad };
+    let plaintext = match envelope.algorithm {
+        Algorithm::XChaCha20Poly1305 => XChaCha20Poly1305::new(key.into())
+            .decrypt(envelope.nonce.as_slice().into(), payload),
+        Algorithm::Aes256Gcm => {
+            Aes256Gcm::new(key.into()).decrypt(envelope.nonce.as_slice().into(), payload)
+        }
+    };
+    plaintext.map_err(|_| Error::Crypto)
+}
+
+// Seal a profile's content key, or a cookie key for a cookie grant, to the X25519 key
+// `key_id` of its owner or a grantee, for `set_content_key` or as the `wrapped_key` of a
+// grant
+pub fn wrap_key(
+    algorithm: Algorithm,
+    key_id: u32,
+    recipient: &[u8; 32],
+    content_key: &[u8; KEY_LEN],
+) -> Result<Envelope, Error> {
+    seal_to(algorithm, key_id, recipient, content_key)
+}
+
+// Recover a wrapped key with the secret of the X25519 key it was sealed to
+pub fn unwrap_key(wrapped_key: &Envelope, secret: &[u8; 32]) -> Result<[u8; KEY_LEN], Error> {
+    let key = open_with(wrapped_key, secret)?;
+    key.try_into().map_err(|_| Error::Crypto)
+}
+
+// Keyed tag of a domain under a profile's secret. The contract only ever sees the tag, so
+// it cannot tell which site a blinded entry belongs to.
+pub fn domain_tag(profile_secret: &[u8; KEY_LEN], domain: &str) -> DomainHash {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(profile_secret)
+        .expect("HMAC accepts keys of any length");
+    mac.update(normalize_domain(domain).as_bytes());
+    mac.finalize().into_bytes().into()
+}
+
+// Domain to store on a blinded entry in place of the plaintext one
+pub fn blinded_domain(profile_secret: &[u8; KEY_LEN], domain: &str) -> DomainRef {
+    DomainRef::Blinded(domain_tag(profile_secret, domain))
+}
+
+// Tags of a host and each of its parent domains, host first, as passed to
+// `get_cookies_for_host` to look up blinded entries
+pub fn host_tag_chain(profile_secret: &[u8; KEY_LEN], host: &str) -> Vec<DomainHash> {
+    host_domains(host)
+        .into_iter()
+        .map(|domain| domain_tag(profile_secret, domain))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seal_and_open_work() {
+        let key = [5; KEY_LEN];
+        let envelope = seal(Algorithm::XChaCha20Poly1305, 1, &key, b"session=abc").unwrap();
+        assert_eq!(envelope.ephemeral_key, [0; 32]);
+        assert_eq!(open(&envelope, &key).unwrap(), b"session=abc");
+        assert_eq!(open(&envelope, &[6; KEY_LEN]), Err(Error::Crypto));
+
+        let secret = [42; 32];
+        let recipient = public_key(&secret);
+        for algorithm in [Algorithm::XChaCha20Poly1305, Algorithm::Aes256Gcm] {
+            let envelope = seal_to(algorithm, 3, &recipient, b"session=abc").unwrap();
+            assert_eq!(envelope.key_id, 3);
+            assert_eq!(envelope.nonce.len(), algorithm.nonce_len());
+            assert_eq!(open_with(&envelope, &secret).unwrap(), b"session=abc");
+
+            // Wrong key, tampered ciphertext and a swapped header are all rejected
+            assert_eq!(open_with(&envelope, &[1; 32]), Err(Error::Crypto));
+            let mut tampered = envelope.clone();
+            tampered.ciphertext[0] ^= 1;
+            assert_eq!(open_with(&tampered, &secret), Err(Error::Crypto));
+            let relabeled = Envelope { key_id: 4, ..envelope.clone() };
+            assert_eq!(open_with(&relabeled, &secret), Err(Error::Crypto));
+            let rekeyed = Envelope { ephemeral_key: public_key(&[2; 32]), ..envelope.clone() };
+            assert_eq!(open_with(&rekeyed, &secret), Err(Error::Crypto));
+            let truncated = Envelope { nonce: vec![0; 8], ..envelope };
+            assert_eq!(open_with(&truncated, &secret), Err(Error::InvalidNonce));
+        }
+        let low_order = [0; 32];
+        assert!(matches!(
+            seal_to(Algorithm::Aes256Gcm, 1, &low_order, b"x"),
+            Err(Error::InvalidKey)
+        ));
+    }
+
+    #[test]
+    fn profile_grants_open_every_cookie() {
+        let (owner, grantee) = ([42; 32], [43; 32]);
+        let algorithm = Algorithm::XChaCha20Poly1305;
+        let profile_key = new_content_key();
+        assert_ne!(new_content_key(), profile_key);
+        let stored = wrap_key(algorithm, 1, &public_key(&owner), &profile_key).unwrap();
+        let sealed = ["cookie1", "cookie2"].map(|cookie| {
+            let key = cookie_key(&profile_key, cookie);
+            seal(algorithm, 1, &key, cookie.as_bytes()).unwrap()
+        });
+        assert_ne!(cookie_key(&profile_key, "cookie1"), cookie_key(&profile_key, "cookie2"));
+
+        // The owner recovers the profile key and wraps it for the grantee, who derives the
+        // key of each cookie from it
+        let profile_key = unwrap_key(&stored, &owner).unwrap();
+        let wrapped_key = wrap_key(algorithm, 7, &public_key(&grantee), &profile_key).unwrap();
+        assert_eq!(wrapped_key.key_id, 7);
+        assert_eq!(unwrap_key(&wrapped_key, &owner), Err(Error::Crypto));
+        let unwrapped = unwrap_key(&wrapped_key, &grantee).unwrap();
+        for (cookie, envelope) in ["cookie1", "cookie2"].into_iter().zip(&sealed) {
+            let key = cookie_key(&unwrapped, cookie);
+            assert_eq!(open(envelope, &key).unwrap(), cookie.as_bytes());
+        }
+        let swapped = cookie_key(&unwrapped, "cookie2");
+        assert_eq!(open(&sealed[0], &swapped), Err(Error::Crypto));
+    }
+
+    #[test]
+    fn cookie_grants_survive_patches() {
+        let grantee = [43; 32];
+        let algorithm = Algorithm::Aes256Gcm;
+        let key = cookie_key(&new_content_key(), "cookie1");
+        let wrapped_key = wrap_key(algorithm, 2, &public_key(&grantee), &key).unwrap();
+        let registered = seal(algorithm, 1, &key, b"session=abc").unwrap();
+
+        // A patch re-seals the cookie under the same key with a fresh nonce
+        let patched = seal(algorithm, 1, &key, b"session=def").unwrap();
+        assert_ne!(patched.nonce, registered.nonce);
+        let unwrapped = unwrap_key(&wrapped_key, &grantee).unwrap();
+        assert_eq!(open(&registered, &unwrapped).unwrap(), b"session=abc");
+        assert_eq!(open(&patched, &unwrapped).unwrap(), b"session=def");
+    }
+
+    #[test]
+    fn legacy_envelopes_open() {
+        let key = [5; KEY_LEN];
+        let (algorithm, key_id) = (Algorithm::XChaCha20Poly1305, 2);
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let aad = (LEGACY_ENVELOPE_VERSION, algorithm, key_id).encode();
+        let payload = Payload { msg: b"session=abc", aad: &aad };
+        let envelope = Envelope {
+            version: LEGACY_ENVELOPE_VERSION,
+            algorithm,
+            key_id,
+            ephemeral_key: [0; 32],
+            nonce: nonce.to_vec(),
+            ciphertext: XChaCha20Poly1305::new(&key.into()).encrypt(&nonce, payload).unwrap(),
+        };
+        assert_eq!(open(&envelope, &key).unwrap(), b"session=abc");
+        let unknown = Envelope { version: 3, ..envelope };
+        assert_eq!(open(&unknown, &key), Err(Error::UnsupportedVersion));
+    }
+
+    #[test]
+    fn domain_tags_work() {
+        let secret = [7; KEY_LEN];
+        let tag = domain_tag(&secret, "example.com");
+        assert_eq!(domain_tag(&secret, ".Example.COM"), tag);
+        assert_ne!(domain_tag(&[8; KEY_LEN], "example.com"), tag);
+        assert_eq!(blinded_domain(&secret, "example.com"), DomainRef::Blinded(tag));
+
+        let chain = host_tag_chain(&secret, "www.example.com");
+        assert_eq!(chain.len(), 3);
+        assert_eq!(chain[0], domain_tag(&secret, "www.example.com"));
+        assert_eq!(chain[1], tag);
+    }
+}
@@ -0,0 +1,472 @@
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+// Deploys cookie contracts from uploaded code, so that every user can own a private instance
+// at an address fixed by their account and a salt of their choosing. Doubles as the registry
+// of per-user vaults, which it moves to newer code on request of their owners. Vaults are
+// registered by address and their owner is asked of the vault itself, so the registry stays
+// right when a vault changes hands.
+
+#[ink::contract]
+mod cookie_factory {
+    use cookie_contract::CookieContractRef;
+    use ink::{
+        codegen::{EmitEvent, TraitCallBuilder},
+        env::call::FromAccountId,
+        prelude::{format, string::String, vec::Vec},
+        storage::Mapping,
+        ToAccountId,
+    };
+
+    /// Custom error type for the factory
+    #[allow(clippy::enum_variant_names)]
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub enum Error {
+        NotAuthorized,
+        InkEnvError(String),
+        SaltAlreadyUsed,
+        VaultAlreadyExists,
+        VaultNotFound,
+        VaultUpToDate,
+        UpgradeFailed,  // The vault refused the upgrade
+        CountOverflow,
+    }
+
+    /// Largest page `get_deployments` returns
+    pub const MAX_PAGE_SIZE: u32 = 100;
+
+    // Chosen by the deploying user to fix the address of their contract
+    pub type Salt = [u8; 32];
+
+    // One cookie contract deployed through the factory
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Deployment {
+        pub contract: AccountId,
+        pub code_hash: Hash,
+        pub salt: Salt,
+        pub block: BlockNumber,
+    }
+
+    // The private vault of one user
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Vault {
+        pub contract: AccountId,
+        pub code_hash: Hash,  // Code the registry deployed or last upgraded the vault to
+        pub created_at: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct ContractDeployed {
+        #[ink(topic)]
+        pub owner: AccountId,
+        #[ink(topic)]
+        pub contract_address: AccountId,
+        pub code_hash: Hash,
+        pub salt: Salt,
+    }
+
+    #[ink(event)]
+    pub struct VaultCreated {
+        #[ink(topic)]
+        pub owner: AccountId,
+        pub vault: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct VaultUpgraded {
+        #[ink(topic)]
+        pub owner: AccountId,
+        pub vault: AccountId,
+        pub code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct CodeHashChanged {
+        pub code_hash: Hash,
+    }
+
+    #[ink(storage)]
+    pub struct CookieFactory {
+        owner: AccountId,
+        cookie_code_hash: Hash,  // Code new deployments are instantiated from
+        deployments: Mapping<(AccountId, u32), Deployment>,  // Deployments per owner, in order
+        deployment_count: Mapping<AccountId, u32>,
+        salts: Mapping<(AccountId, Salt), AccountId>,  // (owner, salt) -> contract
+        vaults: Mapping<AccountId, Vault>,  // Vaults by address
+        created_vaults: Mapping<AccountId, AccountId>,  // At most one vault created per user
+        vault_count: u32,
+    }
+
+    impl CookieFactory {
+        #[ink(constructor)]
+        pub fn new(cookie_code_hash: Hash) -> Self {
+            Self {
+                owner: Self::env().caller(),
+                cookie_code_hash,
+                deployments: Mapping::default(),
+                deployment_count: Mapping::default(),
+                salts: Mapping::default(),
+                vaults: Mapping::default(),
+                created_vaults: Mapping::default(),
+                vault_count: 0,
+            }
+        }
+
+        // Deploy a cookie contract owned by the caller. The salt keeps the address
+        // deterministic, so a client can find its contract again from the salt alone.
+        #[ink(message)]
+        pub fn deploy(&mut self, salt: Salt) -> Result<AccountId, Error> {
+            self.instantiate(self.env().caller(), salt, false)
+        }
+
+        // Deploy the caller's private vault and register it
+        #[ink(message)]
+        pub fn create_vault(&mut self, salt: Salt) -> Result<AccountId, Error> {
+            let owner = self.env().caller();
+            if self.created_vaults.contains(owner) {
+                return Err(Error::VaultAlreadyExists);
+            }
+            let vault_count = self.vault_count.checked_add(1).ok_or(Error::CountOverflow)?;
+
+            let contract = self.instantiate(owner, salt, true)?;
+            let vault = Vault {
+                contract,
+                code_hash: self.cookie_code_hash,
+                created_at: self.env().block_number(),
+            };
+            self.vaults.insert(contract, &vault);
+            self.created_vaults.insert(owner, &contract);
+            self.vault_count = vault_count;
+            // The cookie contract's events are in scope as well, so name the emitting contract
+            EmitEvent::<CookieFactory>::emit_event(
+                self.env(),
+                VaultCreated {
+                    owner,
+                    vault: contract,
+                },
+            );
+            Ok(contract)
+        }
+
+        // Move a vault owned by the caller to the code new vaults are deployed from. Its
+        // owner then runs `migrate` on the vault to bring old storage up to date.
+        #[ink(message)]
+        pub fn upgrade_vault(&mut self, vault_address: AccountId) -> Result<Hash, Error> {
+            let owner = self.env().caller();
+            let mut vault = self.vaults.get(vault_address).ok_or(Error::VaultNotFound)?;
+            let mut contract: CookieContractRef = FromAccountId::from_account_id(vault.contract);
+            let vault_owner = contract
+                .call()
+                .get_owner()
+                .try_invoke()
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?;
+            if vault_owner != owner {
+                return Err(Error::NotAuthorized);
+            }
+            let code_hash = self.cookie_code_hash;
+            if vault.code_hash == code_hash {
+                return Err(Error::VaultUpToDate);
+            }
+
+            contract
+                .call_mut()
+                .upgrade(code_hash)
+                .try_invoke()
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?
+                .map_err(|_| Error::UpgradeFailed)?;
+
+            vault.code_hash = code_hash;
+            self.vaults.insert(vault_address, &vault);
+            EmitEvent::<CookieFactory>::emit_event(
+                self.env(),
+                VaultUpgraded {
+                    owner,
+                    vault: vault.contract,
+                    code_hash,
+                },
+            );
+            Ok(code_hash)
+        }
+
+        // Get a registered vault by its address
+        #[ink(message)]
+        pub fn get_vault(&self, vault_address: AccountId) -> Option<Vault> {
+            self.vaults.get(vault_address)
+        }
+
+        // Get the address of the vault a user created, whoever owns it now
+        #[ink(message)]
+        pub fn get_created_vault(&self, creator: AccountId) -> Option<AccountId> {
+            self.created_vaults.get(creator)
+        }
+
+        #[ink(message)]
+        pub fn get_vault_count(&self) -> u32 {
+            self.vault_count
+        }
+
+        // Change the code later deployments use (factory owner only); existing contracts
+        // are not touched
+        #[ink(message)]
+        pub fn set_cookie_code_hash(&mut self, code_hash: Hash) -> Result<(), Error> {
+            if self.env().caller() != self.owner {
+                return Err(Error::NotAuthorized);
+            }
+            self.cookie_code_hash = code_hash;
+            EmitEvent::<CookieFactory>::emit_event(self.env(), CodeHashChanged { code_hash });
+            Ok(())
+        }
+
+        #[ink(message)]
+        pub fn get_cookie_code_hash(&self) -> Hash {
+            self.cookie_code_hash
+        }
+
+        // Get the contract an owner deployed with a salt
+        #[ink(message)]
+        pub fn get_deployment(&self, owner: AccountId, salt: Salt) -> Option<AccountId> {
+            self.salts.get((owner, salt))
+        }
+
+        // Get up to `limit` deployments of an owner, oldest first, starting at `start`
+        #[ink(message)]
+        pub fn get_deployments(&self, owner: AccountId, start: u32, limit: u32) -> Vec<Deployment> {
+            let end = start
+                .saturating_add(limit.min(MAX_PAGE_SIZE))
+                .min(self.get_deployment_count(owner));
+            (start..end)
+                .filter_map(|position| self.deployments.get((owner, position)))
+                .collect()
+        }
+
+        #[ink(message)]
+        pub fn get_deployment_count(&self, owner: AccountId) -> u32 {
+            self.deployment_count.get(owner).unwrap_or(0)
+        }
+
+        #[ink(message)]
+        pub fn get_owner(&self) -> AccountId {
+            self.owner
+        }
+
+        // Instantiate a cookie contract or vault for `owner` and record the deployment
+        fn instantiate(
+            &mut self,
+            owner: AccountId,
+            salt: Salt,
+            vault: bool,
+        ) -> Result<AccountId, Error> {
+            if self.salts.contains((owner, salt)) {
+                return Err(Error::SaltAlreadyUsed);
+            }
+            let count = self.deployment_count.get(owner).unwrap_or(0);
+            let next_count = count.checked_add(1).ok_or(Error::CountOverflow)?;
+
+            // The owner is part of the constructor input, so equal salts of different
+            // users still give different addresses
+            let constructor = if vault {
+                CookieContractRef::new_vault(owner)
+            } else {
+                CookieContractRef::new_for(owner)
+            };
+            let contract: CookieContractRef = constructor
+                .code_hash(self.cookie_code_hash)
+                .endowment(0)
+                .salt_bytes(salt)
+                .try_instantiate()
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?;
+            let contract_address = contract.to_account_id();
+
+            let deployment = Deployment {
+                contract: contract_address,
+                code_hash: self.cookie_code_hash,
+                salt,
+                block: self.env().block_number(),
+            };
+            self.deployments.insert((owner, count), &deployment);
+            self.deployment_count.insert(owner, &next_count);
+            self.salts.insert((owner, salt), &contract_address);
+
+            EmitEvent::<CookieFactory>::emit_event(
+                self.env(),
+                ContractDeployed {
+                    owner,
+                    contract_address,
+                    code_hash: self.cookie_code_hash,
+                    salt,
+                },
+            );
+            Ok(contract_address)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[ink::test]
+        fn code_hash_is_owner_only() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut factory = CookieFactory::new(Hash::from([1; 32]));
+            assert_eq!(factory.get_owner(), accounts.alice);
+            assert_eq!(factory.get_cookie_code_hash(), Hash::from([1; 32]));
+            assert_eq!(factory.get_deployment_count(accounts.alice), 0);
+            assert_eq!(factory.get_deployments(accounts.alice, 0, 10), Vec::new());
+            assert_eq!(factory.get_deployment(accounts.alice, [0; 32]), None);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                factory.set_cookie_code_hash(Hash::from([2; 32])),
+                Err(Error::NotAuthorized)
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(factory.set_cookie_code_hash(Hash::from([2; 32])), Ok(()));
+            assert_eq!(factory.get_cookie_code_hash(), Hash::from([2; 32]));
+        }
+
+        #[ink::test]
+        fn vaults_are_looked_up_by_address() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut factory = CookieFactory::new(Hash::from([1; 32]));
+            assert_eq!(factory.get_vault(accounts.django), None);
+            assert_eq!(factory.get_created_vault(accounts.alice), None);
+            assert_eq!(factory.get_vault_count(), 0);
+            assert_eq!(factory.upgrade_vault(accounts.django), Err(Error::VaultNotFound));
+
+            // Counters refuse to wrap around before anything is deployed
+            factory.vault_count = u32::MAX;
+            assert_eq!(factory.create_vault([1; 32]), Err(Error::CountOverflow));
+            factory.deployment_count.insert(accounts.alice, &u32::MAX);
+            assert_eq!(factory.deploy([1; 32]), Err(Error::CountOverflow));
+        }
+    }
+
+    #[cfg(all(test, feature = "e2e-tests"))]
+    mod e2e_tests {
+        use super::*;
+        use ink_e2e::build_message;
+
+        type E2EResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+        #[ink_e2e::test(additional_contracts = "../Cargo.toml")]
+        async fn deploy_gives_each_user_a_contract(
+            mut client: ink_e2e::Client<C, E>,
+        ) -> E2EResult<()> {
+            let code_hash = client
+                .upload("cookie_contract", &ink_e2e::alice(), None)
+                .await
+                .expect("upload failed")
+                .code_hash;
+            let constructor = CookieFactoryRef::new(code_hash);
+            let factory_id = client
+                .instantiate("cookie_factory", &ink_e2e::alice(), constructor, 0, None)
+                .await
+                .expect("instantiate failed")
+                .account_id;
+
+            // Bob deploys his own contract and owns it
+            let bob = ink_e2e::account_id(ink_e2e::AccountKeyring::Bob);
+            let deploy = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.deploy([7; 32]));
+            let contract_id = client
+                .call(&ink_e2e::bob(), deploy, 0, None)
+                .await
+                .expect("deploy failed")
+                .return_value()
+                .expect("deploy returned an error");
+            let get_owner = build_message::<CookieContractRef>(contract_id.clone())
+                .call(|contract| contract.get_owner());
+            let owner = client.call_dry_run(&ink_e2e::bob(), &get_owner, 0, None).await;
+            assert_eq!(owner.return_value(), bob);
+
+            // The deployment is recorded and its salt cannot be reused
+            let get_deployment = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.get_deployment(bob, [7; 32]));
+            let deployment = client.call_dry_run(&ink_e2e::bob(), &get_deployment, 0, None).await;
+            assert_eq!(deployment.return_value(), Some(contract_id));
+            let deploy = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.deploy([7; 32]));
+            let result = client.call_dry_run(&ink_e2e::bob(), &deploy, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::SaltAlreadyUsed));
+
+            Ok(())
+        }
+
+        #[ink_e2e::test(additional_contracts = "../Cargo.toml")]
+        async fn vaults_are_registered_and_private(
+            mut client: ink_e2e::Client<C, E>,
+        ) -> E2EResult<()> {
+            let code_hash = client
+                .upload("cookie_contract", &ink_e2e::alice(), None)
+                .await
+                .expect("upload failed")
+                .code_hash;
+            let constructor = CookieFactoryRef::new(code_hash);
+            let factory_id = client
+                .instantiate("cookie_factory", &ink_e2e::alice(), constructor, 0, None)
+                .await
+                .expect("instantiate failed")
+                .account_id;
+
+            // Bob gets one vault, found through the registry
+            let bob = ink_e2e::account_id(ink_e2e::AccountKeyring::Bob);
+            let create_vault = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.create_vault([1; 32]));
+            let vault_id = client
+                .call(&ink_e2e::bob(), create_vault, 0, None)
+                .await
+                .expect("create_vault failed")
+                .return_value()
+                .expect("create_vault returned an error");
+            let get_created_vault = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.get_created_vault(bob));
+            let created = client.call_dry_run(&ink_e2e::alice(), &get_created_vault, 0, None).await;
+            assert_eq!(created.return_value(), Some(vault_id.clone()));
+            let get_vault = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.get_vault(vault_id.clone()));
+            let vault = client.call_dry_run(&ink_e2e::alice(), &get_vault, 0, None).await;
+            let vault = vault.return_value().expect("vault not registered");
+            assert_eq!(vault.contract, vault_id);
+            assert_eq!(vault.code_hash, code_hash);
+            let create_vault = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.create_vault([2; 32]));
+            let result = client.call_dry_run(&ink_e2e::bob(), &create_vault, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::VaultAlreadyExists));
+            let upgrade_vault = build_message::<CookieFactoryRef>(factory_id.clone())
+                .call(|factory| factory.upgrade_vault(vault_id.clone()));
+            let result = client.call_dry_run(&ink_e2e::bob(), &upgrade_vault, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::VaultUpToDate));
+            let result = client.call_dry_run(&ink_e2e::alice(), &upgrade_vault, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::NotAuthorized));
+
+            // The vault only takes new code from the registry, not from its owner directly
+            let upgrade = build_message::<CookieContractRef>(vault_id.clone())
+                .call(|contract| contract.upgrade(code_hash));
+            let result = client.call_dry_run(&ink_e2e::bob(), &upgrade, 0, None).await;
+            assert!(result.return_value().is_err());
+
+            // Only Bob can list the cookies of his vault
+            let is_vault = build_message::<CookieContractRef>(vault_id.clone())
+                .call(|contract| contract.is_vault());
+            let result = client.call_dry_run(&ink_e2e::alice(), &is_vault, 0, None).await;
+            assert!(result.return_value());
+            let get_owned_cookies = build_message::<CookieContractRef>(vault_id.clone())
+                .call(|contract| contract.get_owned_cookies(bob, 0, 10));
+            let result = client.call_dry_run(&ink_e2e::alice(), &get_owned_cookies, 0, None).await;
+            assert!(result.return_value().items.is_empty());
+
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,236 @@
+// Cookie contract as of storage version 1, kept to test upgrades from deployed storage. Only
+// what is needed to write cookies is left; the names of the contract and its fields fix
+// where values are stored and must stay as they are.
+#![cfg_attr(not(feature = "std"), no_std, no_main)]
+
+pub use self::cookie_contract::CookieContractRef;
+
+#[ink::contract]
+mod cookie_contract {
+    use ink::{
+        prelude::string::String,
+        prelude::vec::Vec,
+        storage::{
+            traits::{AutoKey, Packed, StorageKey},
+            Lazy, Mapping,
+        },
+    };
+
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub enum Error {
+        CookieAlreadyExists,
+        NotAuthorized,
+        UpgradeFailed,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum Role {
+        Admin,
+    }
+
+    // Typed cookies are never written here, so `metadata` and `envelope` are always None,
+    // which encodes the same whatever they hold
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct CookieEntry {
+        profile: String,
+        cookie: String,
+        domain: String,
+        expiration_date: Vec<u8>,
+        name: Vec<u8>,
+        secure: Vec<u8>,
+        path: Vec<u8>,
+        value: Vec<u8>,
+        metadata: Option<()>,
+        envelope: Option<()>,
+        created_at: BlockNumber,
+        owner: AccountId,
+        transaction_id: u64,
+        revision: u32,
+        oldest_revision: u32,
+    }
+
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct CookieRevision {
+        revision: u32,
+        value: Vec<u8>,
+        expiration_date: Vec<u8>,
+        metadata: Option<()>,
+        envelope: Option<()>,
+        transaction_id: u64,
+        block: BlockNumber,
+    }
+
+    type CookieKey = (AccountId, String);
+
+    type GroupKey = (AccountId, String);
+
+    #[ink::storage_item]
+    #[derive(Debug)]
+    pub struct IndexedList<S: Packed, T: Packed, KEY: StorageKey = AutoKey> {
+        len: Mapping<S, u32>,
+        items: Mapping<(S, u32), T>,
+        positions: Mapping<(S, T), u32>,
+    }
+
+    impl<S: Packed, T: Packed, KEY: StorageKey> Default for IndexedList<S, T, KEY> {
+        fn default() -> Self {
+            Self {
+                len: Mapping::default(),
+                items: Mapping::default(),
+                positions: Mapping::default(),
+            }
+        }
+    }
+
+    impl<S, T, KEY> IndexedList<S, T, KEY>
+    where
+        S: Packed,
+        T: Packed + scale::EncodeLike,
+        KEY: StorageKey,
+    {
+        fn push(&mut self, scope: &S, item: &T) {
+            let len = self.len.get(scope).unwrap_or(0);
+            self.items.insert((scope, len), item);
+            self.positions.insert((scope, item), &len);
+            self.len.insert(scope, &(len + 1));
+        }
+    }
+
+    // Fields without a value in the root cell (mappings, lazies and lists) that this
+    // fixture never writes are left out
+    #[ink(storage)]
+    pub struct CookieContract {
+        owner: AccountId,
+        pending_owner: Option<AccountId>,
+        roles: Mapping<(Role, AccountId), ()>,
+        paused: bool,
+        storage_version: Lazy<u32>,
+        cookie_count: u32,
+        cookies: Mapping<CookieKey, CookieEntry>,
+        cookie_list: IndexedList<(), CookieKey>,
+        owned_cookies: IndexedList<AccountId, String>,
+        profile_cookies: IndexedList<GroupKey, String>,
+        domain_cookies: IndexedList<GroupKey, String>,
+        transaction_id_counter: u64,
+        compartment_id_counter: u32,
+        profile_id_counter: u32,
+        domain_id_counter: u32,
+        cookie_revisions: Mapping<(CookieKey, u32), CookieRevision>,
+        max_revisions: u32,
+        max_batch_size: u32,
+    }
+
+    impl CookieContract {
+        #[ink(constructor)]
+        pub fn new() -> Self {
+            let owner = Self::env().caller();
+            let mut roles = Mapping::default();
+            roles.insert((Role::Admin, owner), &());
+            let mut storage_version = Lazy::default();
+            storage_version.set(&1);
+            Self {
+                owner,
+                pending_owner: None,
+                roles,
+                paused: false,
+                storage_version,
+                cookie_count: 0,
+                cookies: Mapping::default(),
+                cookie_list: IndexedList::default(),
+                owned_cookies: IndexedList::default(),
+                profile_cookies: IndexedList::default(),
+                domain_cookies: IndexedList::default(),
+                transaction_id_counter: 0,
+                compartment_id_counter: 0,
+                profile_id_counter: 0,
+                domain_id_counter: 0,
+                cookie_revisions: Mapping::default(),
+                max_revisions: 16,
+                max_batch_size: 64,
+            }
+        }
+
+        // Register a new cookie of the caller
+        #[ink(message)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn register_cookie(
+            &mut self,
+            profile: String,
+            cookie: String,
+            expiration_date: Vec<u8>,
+            name: Vec<u8>,
+            secure: Vec<u8>,
+            path: Vec<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), Error> {
+            let owner = self.env().caller();
+            let key = (owner, cookie.clone());
+            if self.cookies.contains(&key) {
+                return Err(Error::CookieAlreadyExists);
+            }
+
+            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
+            let block = self.env().block_number();
+            let revision = CookieRevision {
+                revision: 0,
+                value: value.clone(),
+                expiration_date: expiration_date.clone(),
+                metadata: None,
+                envelope: None,
+                transaction_id: self.transaction_id_counter,
+                block,
+            };
+            self.cookie_revisions.insert((&key, 0), &revision);
+
+            // Storage version 1 stored the cookie's identifier in place of its domain
+            let entry = CookieEntry {
+                profile: profile.clone(),
+                cookie: cookie.clone(),
+                domain: cookie.clone(),
+                expiration_date,
+                name,
+                secure,
+                path,
+                value,
+                metadata: None,
+                envelope: None,
+                created_at: block,
+                owner,
+                transaction_id: self.transaction_id_counter,
+                revision: 0,
+                oldest_revision: 0,
+            };
+            self.cookies.insert(&key, &entry);
+            self.cookie_list.push(&(), &key);
+            self.cookie_count = self.cookie_count.wrapping_add(1);
+            self.owned_cookies.push(&owner, &cookie);
+            self.profile_cookies.push(&(owner, profile), &cookie);
+            self.domain_cookies.push(&(owner, cookie.clone()), &cookie);
+            Ok(())
+        }
+
+        // Replace the contract code, keeping its storage (contract owner only)
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<(), Error> {
+            if self.env().caller() != self.owner {
+                return Err(Error::NotAuthorized);
+            }
+            self.env()
+                .set_code_hash(&code_hash)
+                .map_err(|_| Error::UpgradeFailed)
+        }
+    }
+}
@@ -1,14 +1,25 @@
 #![cfg_attr(not(feature = "std"), no_std, no_main)]
 
+pub use self::cookie_contract::{
+    domain_hash, host_domains, host_hash_chain, normalize_domain, Algorithm, CookieContractRef,
+    DomainHash, DomainRef, Envelope, ENVELOPE_VERSION,
+};
+
 #[ink::contract]
 mod cookie_contract {
     use ink::{
+        prelude::boxed::Box,
+        prelude::format,
         prelude::string::String,
         prelude::vec::Vec,
-        storage::Mapping,
+        storage::{
+            traits::{AutoKey, Packed, StorageKey},
+            Lazy, Mapping,
+        },
     };
 
     /// Custom error type for the contract
+    #[allow(clippy::enum_variant_names)]
     #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
     #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
     pub enum Error {
@@ -17,6 +28,191 @@ mod cookie_contract {
         NotAuthorized,
         InkEnvError(String),
         InvalidKey,
+        CompartmentNotFound,
+        ProfileNotFound,
+        DomainNotFound,
+        DomainAlreadyExists,
+        CompartmentNotEmpty,
+        ProfileNotEmpty,
+        RevisionNotFound,
+        InvalidLimit,
+        BatchTooLarge,
+        BatchItemFailed(u32, Box<Error>),  // Index of the first rejected item and why
+        InvalidExpiration,
+        SameSiteNoneRequiresSecure,
+        PartitionedRequiresSecure,
+        EmptyPayload,
+        PayloadTooLarge,
+        UnsupportedEnvelopeVersion,
+        InvalidNonce,
+        KeyNotFound,
+        InvalidEncoding,
+        KeyAlreadySet,
+        KeyRevoked,
+        GrantNotFound,
+        InvalidGrantee,
+        MissingRole(Role),
+        InvalidRole,
+        OutOfScope,
+        Paused,
+        NotPaused,
+        MigrationPending,
+        InvalidDeposit,
+        InvalidRange,
+        DuplicateCookie,
+        MigrationFailed(CookieKey),  // Entry whose stored value or revisions do not decode
+    }
+
+    // Contract-wide roles are granted by admins; operators are authorized by the account
+    // they write cookies for. `Owner` is only held by the contract owner and moves with
+    // ownership.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum Role {
+        Admin,
+        Pauser,
+        Operator,
+        Owner,
+    }
+
+    /// Largest encrypted payload accepted for a typed cookie
+    pub const MAX_PAYLOAD_LEN: u32 = 8192;
+
+    // Mirrors chrome.cookies.SameSiteStatus
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum SameSite {
+        Unspecified,
+        NoRestriction,
+        Lax,
+        Strict,
+    }
+
+    // Plaintext cookie attributes of a typed cookie; name, value, path and the like
+    // travel encrypted in the entry's envelope
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct CookieMetadata {
+        pub secure: bool,
+        pub http_only: bool,
+        pub same_site: SameSite,
+        pub expires_at: Option<u64>,  // Unix seconds, None for session cookies
+        pub host_only: bool,
+        pub partitioned: bool,
+    }
+
+    impl CookieMetadata {
+        // Reject attribute combinations browsers refuse to store
+        fn validate(&self) -> Result<(), Error> {
+            if self.expires_at == Some(0) {
+                return Err(Error::InvalidExpiration);
+            }
+            if self.same_site == SameSite::NoRestriction && !self.secure {
+                return Err(Error::SameSiteNoneRequiresSecure);
+            }
+            if self.partitioned && !self.secure {
+                return Err(Error::PartitionedRequiresSecure);
+            }
+            Ok(())
+        }
+    }
+
+    /// Envelope format version written by current clients
+    pub const ENVELOPE_VERSION: u8 = 2;
+
+    // AEAD used to seal an envelope
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum Algorithm {
+        XChaCha20Poly1305,
+        Aes256Gcm,
+    }
+
+    impl Algorithm {
+        pub fn nonce_len(&self) -> usize {
+            match self {
+                Algorithm::XChaCha20Poly1305 => 24,
+                Algorithm::Aes256Gcm => 12,
+            }
+        }
+    }
+
+    // Scheme of a registered public key; envelopes are sealed to X25519 keys
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum KeyKind {
+        Sr25519,
+        Ed25519,
+        X25519,
+    }
+
+    // Entry of an account's key registry; rotated and revoked keys are kept so historical
+    // cookies can still be decrypted. Keys are set and rotated on the strength of the
+    // account's own signed call alone: ink 4 can only recover ECDSA signatures, so the
+    // contract cannot check that the caller holds the new key or the one it replaces.
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct PublicKeyRecord {
+        pub key_id: u32,
+        pub kind: KeyKind,
+        pub key: [u8; 32],
+        pub created_at: BlockNumber,
+        pub revoked_at: Option<BlockNumber>,
+    }
+
+    // Encrypted payload of a typed cookie, sealed under a key derived from its profile's
+    // content key, which is wrapped to the owner's X25519 key `key_id`. Wrapped keys are
+    // envelopes too, sealed to `key_id` under a key agreed with the one-time
+    // `ephemeral_key`, which is zero on payloads.
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Envelope {
+        pub version: u8,
+        pub algorithm: Algorithm,
+        pub key_id: u32,
+        pub ephemeral_key: [u8; 32],  // Sender's one-time X25519 public key
+        pub nonce: Vec<u8>,
+        pub ciphertext: Vec<u8>,  // Includes the AEAD tag
+    }
+
+    impl Envelope {
+        // Check the envelope's shape; whether `key_id` exists is checked against storage
+        fn validate(&self) -> Result<(), Error> {
+            if self.version != ENVELOPE_VERSION {
+                return Err(Error::UnsupportedEnvelopeVersion);
+            }
+            if self.nonce.len() != self.algorithm.nonce_len() {
+                return Err(Error::InvalidNonce);
+            }
+            if self.ciphertext.is_empty() {
+                return Err(Error::EmptyPayload);
+            }
+            if self.ciphertext.len() > MAX_PAYLOAD_LEN as usize {
+                return Err(Error::PayloadTooLarge);
+            }
+            Ok(())
+        }
     }
 
     #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
@@ -24,289 +220,4688 @@ mod cookie_contract {
         feature = "std",
         derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
     )]
+    // Encrypted fields hold raw ciphertext bytes; entries written before they moved from
+    // base64 `String`s decode unchanged and can be shrunk with `decode_legacy_fields`
     pub struct CookieEntry {
         profile: String,
         cookie: String,
-        expiration_date: String,
-        name: String,
-        secure: String,
-        path: String,
-        value: String,
+        domain: DomainRef,
+        expiration_date: Vec<u8>,
+        name: Vec<u8>,
+        secure: Vec<u8>,
+        path: Vec<u8>,
+        value: Vec<u8>,
+        metadata: Option<CookieMetadata>,  // Set for typed cookies only
+        envelope: Option<Envelope>,  // Encrypted payload of typed cookies
         created_at: BlockNumber,
         owner: AccountId,
         transaction_id: u64,  // Added field for transaction ID
+        revision: u32,  // Latest revision number
+        oldest_revision: u32,  // Oldest revision still retained
+        expires_at: Option<u64>,  // Unix seconds the contract treats the cookie as gone at
+        // Value the owner sent on creation, held in escrow by the contract and paid back on
+        // removal. Separate from the runtime's storage deposit, which goes to whoever frees
+        // the storage.
+        deposit: Balance,
     }
 
-    #[ink(event)]
-    pub struct CookieRegistered {
-        cookie: String,
-        owner: AccountId,
-        block: BlockNumber,
+    impl CookieEntry {
+        fn key(&self) -> CookieKey {
+            (self.owner, self.cookie.clone())
+        }
+    }
+
+    /// Length of one bucket of the expiry index, in seconds
+    pub const EXPIRY_BUCKET_SECS: u64 = 86_400;
+
+    /// Most buckets a single `get_expiring_between` call may cover
+    pub const MAX_EXPIRY_QUERY_BUCKETS: u64 = 366;
+
+    /// Buckets behind the current one that indexing a past expiry may move the cursor of
+    /// `prune_expired` back to. Writes only take future expiries; past ones come from
+    /// migrations.
+    pub const PRUNE_GRACE_BUCKETS: u64 = 30;
+
+    /// Most index entries and buckets a single `get_expiring_between` call looks at
+    pub const MAX_EXPIRY_SCAN: u32 = 1000;
+
+    // Bucket of the expiry index and position within it a listing resumes at
+    pub type ExpiryCursor = (u64, u32);
+
+    // Bucket of the expiry index a cookie expiring at `expires_at` (Unix seconds) falls in
+    fn expiry_bucket(expires_at: u64) -> u64 {
+        expires_at / EXPIRY_BUCKET_SECS
+    }
+
+    // Hash a cookie's domain is indexed under for host lookups
+    pub type DomainHash = [u8; 32];
+
+    /// Most domain hashes a host lookup accepts, i.e. labels of the host
+    pub const MAX_HOST_LABELS: u32 = 16;
+
+    /// Most index entries and domains a single host lookup looks at
+    pub const MAX_HOST_SCAN: u32 = 1000;
+
+    // Domain of a host lookup's chain and position within its index a lookup resumes at
+    pub type HostCursor = (u32, u32);
+
+    // Form a domain is hashed or tagged in: lowercase and without a leading dot
+    pub fn normalize_domain(domain: &str) -> String {
+        domain.trim_start_matches('.').to_ascii_lowercase()
+    }
+
+    // A host followed by each of its parent domains
+    pub fn host_domains(host: &str) -> Vec<&str> {
+        let mut domain = host.trim_start_matches('.');
+        let mut domains = Vec::new();
+        loop {
+            domains.push(domain);
+            match domain.split_once('.') {
+                Some((_, parent)) if !parent.is_empty() => domain = parent,
+                _ => return domains,
+            }
+        }
+    }
+
+    // Hash of a plaintext domain as indexed by the contract
+    pub fn domain_hash(domain: &str) -> DomainHash {
+        let mut hash = DomainHash::default();
+        let domain = normalize_domain(domain);
+        ink::env::hash_bytes::<ink::env::hash::Blake2x256>(domain.as_bytes(), &mut hash);
+        hash
+    }
+
+    // Hashes of a host and each of its parent domains, host first, as passed to
+    // `get_cookies_for_host`
+    pub fn host_hash_chain(host: &str) -> Vec<DomainHash> {
+        host_domains(host).into_iter().map(domain_hash).collect()
+    }
+
+    // Domain of a cookie: the plaintext domain, or for blinded entries a keyed tag of it
+    // that the contract cannot map back to a site
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum DomainRef {
+        Plain(String),
+        Blinded(DomainHash),
+    }
+
+    impl DomainRef {
+        // Hash the cookie is indexed under for host lookups
+        fn index_hash(&self) -> DomainHash {
+            match self {
+                DomainRef::Plain(domain) => domain_hash(domain),
+                DomainRef::Blinded(tag) => *tag,
+            }
+        }
+    }
+
+    // Cookies are namespaced per owner so two accounts can use the same cookie name
+    pub type CookieKey = (AccountId, String);
+
+    // Owner plus profile or domain name, used by the listing indexes
+    type GroupKey = (AccountId, String);
+
+    // Owner, profile and domain hash, used by the host index
+    type HostKey = (AccountId, String, DomainHash);
+
+    // Snapshot of a cookie's value as of one register/update
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct CookieRevision {
+        revision: u32,
+        value: Vec<u8>,
+        expiration_date: Vec<u8>,
+        metadata: Option<CookieMetadata>,
+        envelope: Option<Envelope>,
         transaction_id: u64,
+        block: BlockNumber,
+        expires_at: Option<u64>,
+    }
+
+    // Top-level grouping of profiles (e.g. "Personal", "Work")
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Compartment {
+        id: u32,
+        name: String,
+        owner: AccountId,
+        created_at: BlockNumber,
+    }
+
+    // Cookie profile living inside a compartment
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Profile {
+        id: u32,
+        compartment_id: u32,
+        name: String,
+        owner: AccountId,
+        created_at: BlockNumber,
+    }
+
+    // Domain mapped to the profile whose cookies it should load
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Domain {
+        id: u32,
+        profile_id: u32,
+        name: String,
+        owner: AccountId,
+        created_at: BlockNumber,
+    }
+
+    // Bits of `CookieUpdated::changed_fields`
+    pub const FIELD_PROFILE: u16 = 1 << 0;
+    pub const FIELD_EXPIRATION_DATE: u16 = 1 << 1;
+    pub const FIELD_NAME: u16 = 1 << 2;
+    pub const FIELD_SECURE: u16 = 1 << 3;
+    pub const FIELD_PATH: u16 = 1 << 4;
+    pub const FIELD_VALUE: u16 = 1 << 5;
+    pub const FIELD_DOMAIN: u16 = 1 << 6;
+    pub const FIELD_METADATA: u16 = 1 << 7;
+    pub const FIELD_ENVELOPE: u16 = 1 << 8;
+    pub const FIELD_EXPIRES_AT: u16 = 1 << 9;
+
+    // Field-level update; `None` leaves the stored field untouched
+    #[derive(Debug, Default, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub struct CookiePatch {
+        pub profile: Option<String>,
+        pub expiration_date: Option<Vec<u8>>,
+        pub name: Option<Vec<u8>>,
+        pub secure: Option<Vec<u8>>,
+        pub path: Option<Vec<u8>>,
+        pub value: Option<Vec<u8>>,
+        pub domain: Option<DomainRef>,
+        pub metadata: Option<CookieMetadata>,
+        pub envelope: Option<Envelope>,
+        pub expires_at: Option<Option<u64>>,  // `Some(None)` makes the cookie never expire
+    }
+
+    impl CookiePatch {
+        // `now` is the block time in Unix seconds, which a new expiry must lie after
+        fn validate(&self, now: u64) -> Result<(), Error> {
+            if self.expires_at.flatten().is_some_and(|expires_at| expires_at <= now) {
+                return Err(Error::InvalidExpiration);
+            }
+            if let Some(metadata) = &self.metadata {
+                metadata.validate()?;
+                if metadata.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                    return Err(Error::InvalidExpiration);
+                }
+                if self.expires_at.is_some_and(|expires_at| expires_at != metadata.expires_at) {
+                    return Err(Error::InvalidExpiration);
+                }
+            }
+            if let Some(envelope) = &self.envelope {
+                envelope.validate()?;
+            }
+            Ok(())
+        }
+
+        // Write the provided fields into the entry and report which ones changed
+        fn apply(self, entry: &mut CookieEntry) -> u16 {
+            let mut changed = 0;
+            changed |= set_field(self.profile, &mut entry.profile, FIELD_PROFILE);
+            changed |= set_field(self.domain, &mut entry.domain, FIELD_DOMAIN);
+            for (update, field, flag) in [
+                (self.expiration_date, &mut entry.expiration_date, FIELD_EXPIRATION_DATE),
+                (self.name, &mut entry.name, FIELD_NAME),
+                (self.secure, &mut entry.secure, FIELD_SECURE),
+                (self.path, &mut entry.path, FIELD_PATH),
+                (self.value, &mut entry.value, FIELD_VALUE),
+            ] {
+                changed |= set_field(update, field, flag);
+            }
+
+            // Typed cookies carry their expiry in the metadata too; an update of either one
+            // moves both
+            let expires_at = self
+                .expires_at
+                .or_else(|| self.metadata.as_ref().map(|metadata| metadata.expires_at));
+            changed |= set_field(self.metadata.map(Some), &mut entry.metadata, FIELD_METADATA);
+            changed |= set_field(self.envelope.map(Some), &mut entry.envelope, FIELD_ENVELOPE);
+            if let (Some(expires_at), Some(metadata)) = (expires_at, entry.metadata.as_mut()) {
+                changed |= set_field(Some(expires_at), &mut metadata.expires_at, FIELD_METADATA);
+            }
+            changed |= set_field(expires_at, &mut entry.expires_at, FIELD_EXPIRES_AT);
+            changed
+        }
+    }
+
+    // Overwrite the field if an update is given, returning `flag` if its value changed
+    fn set_field<T: PartialEq>(update: Option<T>, field: &mut T, flag: u16) -> u16 {
+        match update {
+            Some(update) if *field != update => {
+                *field = update;
+                flag
+            }
+            _ => 0,
+        }
+    }
+
+    // One cookie of a batch call
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub struct CookieInput {
+        pub profile: String,
+        pub cookie: String,
+        pub expiration_date: Vec<u8>,
+        pub name: Vec<u8>,
+        pub secure: Vec<u8>,
+        pub path: Vec<u8>,
+        pub value: Vec<u8>,
+        pub domain: DomainRef,
+        pub metadata: Option<CookieMetadata>,
+        pub envelope: Option<Envelope>,
+        // Unix seconds; typed cookies must pass the `expires_at` of their metadata
+        pub expires_at: Option<u64>,
+    }
+
+    impl CookieInput {
+        // `now` is the block time in Unix seconds, which the expiry must lie after
+        fn validate(&self, now: u64) -> Result<(), Error> {
+            if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+                return Err(Error::InvalidExpiration);
+            }
+            if let Some(metadata) = &self.metadata {
+                metadata.validate()?;
+                self.envelope.as_ref().ok_or(Error::EmptyPayload)?.validate()?;
+                if self.expires_at != metadata.expires_at {
+                    return Err(Error::InvalidExpiration);
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl From<CookieInput> for CookiePatch {
+        fn from(input: CookieInput) -> Self {
+            Self {
+                profile: Some(input.profile),
+                expiration_date: Some(input.expiration_date),
+                name: Some(input.name),
+                secure: Some(input.secure),
+                path: Some(input.path),
+                value: Some(input.value),
+                domain: Some(input.domain),
+                metadata: input.metadata,
+                envelope: input.envelope,
+                expires_at: Some(input.expires_at),
+            }
+        }
+    }
+
+    // List with O(1) push and swap-remove, kept as index-addressed mappings per scope so
+    // that no call ever decodes a whole collection. Removal moves the last item into the
+    // freed slot, so positions (and page cursors) shift when items are removed.
+    #[ink::storage_item]
+    #[derive(Debug)]
+    pub struct IndexedList<S: Packed, T: Packed, KEY: StorageKey = AutoKey> {
+        len: Mapping<S, u32>,
+        items: Mapping<(S, u32), T>,
+        positions: Mapping<(S, T), u32>,
+    }
+
+    impl<S: Packed, T: Packed, KEY: StorageKey> Default for IndexedList<S, T, KEY> {
+        fn default() -> Self {
+            Self {
+                len: Mapping::default(),
+                items: Mapping::default(),
+                positions: Mapping::default(),
+            }
+        }
+    }
+
+    impl<S, T, KEY> IndexedList<S, T, KEY>
+    where
+        S: Packed,
+        T: Packed + scale::EncodeLike,
+        KEY: StorageKey,
+    {
+        fn len(&self, scope: &S) -> u32 {
+            self.len.get(scope).unwrap_or(0)
+        }
+
+        fn push(&mut self, scope: &S, item: &T) {
+            let len = self.len(scope);
+            self.items.insert((scope, len), item);
+            self.positions.insert((scope, item), &len);
+            self.len.insert(scope, &(len + 1));
+        }
+
+        fn remove(&mut self, scope: &S, item: &T) {
+            let Some(position) = self.positions.take((scope, item)) else {
+                return;
+            };
+
+            let last = self.len(scope) - 1;
+            if position != last {
+                if let Some(moved) = self.items.get((scope, last)) {
+                    self.items.insert((scope, position), &moved);
+                    self.positions.insert((scope, &moved), &position);
+                }
+            }
+            self.items.remove((scope, last));
+            if last == 0 {
+                self.len.remove(scope);
+            } else {
+                self.len.insert(scope, &last);
+            }
+        }
+
+        fn contains(&self, scope: &S, item: &T) -> bool {
+            self.positions.contains((scope, item))
+        }
+
+        fn get(&self, scope: &S, position: u32) -> Option<T> {
+            self.items.get((scope, position))
+        }
+
+        fn page(&self, scope: &S, start: u32, limit: u32) -> Page<T> {
+            let len = self.len(scope);
+            let start = start.min(len);
+            let end = start.saturating_add(limit.min(MAX_PAGE_SIZE)).min(len);
+            Page {
+                items: (start..end)
+                    .filter_map(|position| self.items.get((scope, position)))
+                    .collect(),
+                next: (end < len).then_some(end),
+            }
+        }
+
+        fn to_vec(&self, scope: &S) -> Vec<T> {
+            (0..self.len(scope))
+                .filter_map(|position| self.items.get((scope, position)))
+                .collect()
+        }
+    }
+
+    /// Largest page any listing message returns
+    pub const MAX_PAGE_SIZE: u32 = 100;
+
+    // One page of a listing plus the cursor to pass as `start` for the next one
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub struct Page<T, C = u32> {
+        pub items: Vec<T>,
+        pub next: Option<C>,
+    }
+
+    impl<T, C> Default for Page<T, C> {
+        fn default() -> Self {
+            Self {
+                items: Vec::new(),
+                next: None,
+            }
+        }
+    }
+
+    // Whether a write created a new cookie or updated an existing one
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub enum WriteOutcome {
+        Created,
+        Updated,
+    }
+
+    // Per-item result of a batch call
+    #[derive(Debug, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(feature = "std", derive(scale_info::TypeInfo))]
+    pub struct BatchItemResult {
+        pub cookie: String,
+        pub outcome: WriteOutcome,
+        pub transaction_id: u64,
+    }
+
+    // What a grant shares: one cookie, or every cookie of a profile
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum GrantTarget {
+        Cookie(String),
+        Profile(String),
+    }
+
+    // Write access implies read access
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum Permission {
+        Read,
+        Write,
+    }
+
+    // Access another account has to some of the owner's cookies. `wrapped_key` holds the
+    // target's key sealed to one of the grantee's X25519 keys: the cookie's key for a
+    // cookie grant, the profile's content key, which every cookie key derives from, for a
+    // profile grant.
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Grant {
+        pub permission: Permission,
+        pub wrapped_key: Envelope,
+        pub expires_at: Option<BlockNumber>,  // First block the grant no longer applies
+        pub created_at: BlockNumber,
+    }
+
+    // Owner and target of a grant, as listed for its grantee
+    pub type GrantKey = (AccountId, GrantTarget);
+
+    // What a transaction did to its cookie
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub enum TxKind {
+        Register,
+        Update,
+        Delete,
+    }
+
+    // Log entry of one transaction, for clients syncing by last seen transaction id.
+    // `content_hash` is the Blake2x256 hash of the SCALE-encoded entry as written, or as
+    // removed for deletions.
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct TxRecord {
+        pub transaction_id: u64,
+        pub kind: TxKind,
+        pub cookie: CookieKey,
+        pub caller: AccountId,
+        pub block: BlockNumber,
+        pub content_hash: [u8; 32],
+    }
+
+    /// Storage layout version this code reads and writes; `migrate` brings older storage
+    /// up to it
+    pub const STORAGE_VERSION: u32 = 6;
+
+    // Encrypted fields were stored as base64 text before storage version 1
+    fn decode_legacy(field: &[u8]) -> Result<Vec<u8>, Error> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        STANDARD.decode(field).map_err(|_| Error::InvalidEncoding)
     }
 
-    #[ink(event)]
-    pub struct CookieUpdated {
-        cookie: String,
-        owner: AccountId,
-        block: BlockNumber,
-        transaction_id: u64,
-    }
+    // Stored value read or written as its bare encoding
+    struct RawValue(Vec<u8>);
+
+    impl scale::Encode for RawValue {
+        fn size_hint(&self) -> usize {
+            self.0.len()
+        }
+
+        fn encode_to<O: scale::Output + ?Sized>(&self, dest: &mut O) {
+            dest.write(&self.0)
+        }
+    }
+
+    impl scale::Decode for RawValue {
+        fn decode<I: scale::Input>(input: &mut I) -> Result<Self, scale::Error> {
+            let len = input.remaining_len()?.ok_or("unknown length")?;
+            let mut bytes = ink::prelude::vec![0; len];
+            input.read(&mut bytes)?;
+            Ok(Self(bytes))
+        }
+    }
+
+    // Read a stored value as its bare encoding. The input `RawValue` decodes from may run
+    // past the value, as it does off-chain, so it is cut to the stored length.
+    fn read_raw<K: scale::Encode>(root: ink::primitives::Key, key: K) -> Option<Vec<u8>> {
+        let len = ink::env::contains_contract_storage(&(root, &key))?;
+        let RawValue(mut bytes) = ink::env::get_contract_storage(&(root, key)).ok()??;
+        bytes.truncate(len as usize);
+        Some(bytes)
+    }
+
+    // Envelope as stored before storage version 6, which added `ephemeral_key`. Envelopes of
+    // that layout keep `version` 1, so clients open them with the header they were sealed
+    // under.
+    #[derive(scale::Decode)]
+    struct LegacyEnvelope {
+        version: u8,
+        algorithm: Algorithm,
+        key_id: u32,
+        nonce: Vec<u8>,
+        ciphertext: Vec<u8>,
+    }
+
+    impl From<LegacyEnvelope> for Envelope {
+        fn from(legacy: LegacyEnvelope) -> Self {
+            Self {
+                version: legacy.version,
+                algorithm: legacy.algorithm,
+                key_id: legacy.key_id,
+                ephemeral_key: [0; 32],
+                nonce: legacy.nonce,
+                ciphertext: legacy.ciphertext,
+            }
+        }
+    }
+
+    // Re-encode the legacy envelope found `offset` bytes into a stored value in the current
+    // layout, keeping the bytes around it
+    fn upgrade_envelope(
+        bytes: &[u8],
+        offset: usize,
+        optional: bool,
+    ) -> Result<Vec<u8>, scale::Error> {
+        use scale::{Decode, Encode};
+
+        let (head, mut rest) = bytes.split_at_checked(offset).ok_or("value too short")?;
+        let envelope = if optional {
+            Option::<LegacyEnvelope>::decode(&mut rest)?.map(Envelope::from).encode()
+        } else {
+            Envelope::from(LegacyEnvelope::decode(&mut rest)?).encode()
+        };
+        Ok([head, &envelope, rest].concat())
+    }
+
+    // Bytes of a stored value up to the field after the leading fields `P`
+    fn prefix_len<P: scale::Decode>(bytes: &[u8]) -> Result<usize, scale::Error> {
+        let mut input = bytes;
+        P::decode(&mut input)?;
+        Ok(bytes.len() - input.len())
+    }
+
+    // Decode an entry written at storage version `from_version`. Before version 4 its domain
+    // was a bare `String`, which encodes as a plaintext `DomainRef` without the variant
+    // index 0; before version 2 the trailing fields were missing and are filled with zeros,
+    // which decode as None or 0.
+    fn upgrade_entry(mut bytes: Vec<u8>, from_version: u32) -> Result<CookieEntry, scale::Error> {
+        use scale::{DecodeAll, Encode};
+
+        if from_version < 4 {
+            bytes.insert(prefix_len::<(String, String)>(&bytes)?, 0);
+        }
+        if from_version < 2 {
+            let added = (None::<u64>, Balance::default()).encoded_size();
+            bytes.resize(bytes.len() + added, 0);
+        }
+        if from_version < 6 {
+            type Prefix = (String, String, DomainRef, [Vec<u8>; 5], Option<CookieMetadata>);
+            bytes = upgrade_envelope(&bytes, prefix_len::<Prefix>(&bytes)?, true)?;
+        }
+        CookieEntry::decode_all(&mut &bytes[..])
+    }
+
+    // Decode a revision written at storage version `from_version`, as `upgrade_entry` does
+    fn upgrade_revision(
+        mut bytes: Vec<u8>,
+        from_version: u32,
+    ) -> Result<CookieRevision, scale::Error> {
+        use scale::{DecodeAll, Encode};
+
+        if from_version < 2 {
+            bytes.resize(bytes.len() + None::<u64>.encoded_size(), 0);
+        }
+        if from_version < 6 {
+            type Prefix = (u32, Vec<u8>, Vec<u8>, Option<CookieMetadata>);
+            bytes = upgrade_envelope(&bytes, prefix_len::<Prefix>(&bytes)?, true)?;
+        }
+        CookieRevision::decode_all(&mut &bytes[..])
+    }
+
+    // Decode a grant in either layout. Grants are not listed anywhere `migrate` can reach
+    // them all, so profile grants of profiles without cookies may still be in the legacy
+    // one. A current-layout grant carries either this envelope version or, once migrated,
+    // no ephemeral key.
+    fn decode_grant(bytes: &[u8]) -> Option<Grant> {
+        use scale::DecodeAll;
+
+        let current = Grant::decode_all(&mut &bytes[..]).ok().filter(|grant| {
+            grant.wrapped_key.version == ENVELOPE_VERSION
+                || grant.wrapped_key.ephemeral_key == [0; 32]
+        });
+        current.or_else(|| {
+            let bytes = upgrade_envelope(bytes, prefix_len::<Permission>(bytes).ok()?, false);
+            Grant::decode_all(&mut &bytes.ok()?[..]).ok()
+        })
+    }
+
+    /// Largest number of profiles or domains an operator can be scoped to
+    pub const MAX_OPERATOR_SCOPE: u32 = 16;
+
+    // Device key authorized to act for `account`, so the account's seed never has to live
+    // in the browser. Empty `profiles`/`domains` lists place no restriction.
+    #[derive(Debug, Clone, PartialEq, Eq, scale::Encode, scale::Decode)]
+    #[cfg_attr(
+        feature = "std",
+        derive(scale_info::TypeInfo, ink::storage::traits::StorageLayout)
+    )]
+    pub struct Operator {
+        pub account: AccountId,
+        pub profiles: Vec<String>,
+        pub domains: Vec<String>,
+        pub expires_at: Option<BlockNumber>,  // First block the operator can no longer act
+        pub created_at: BlockNumber,
+    }
+
+    impl Operator {
+        // Blinded entries never match a domain scope, as their domain is unknown here
+        fn allows(&self, profile: &str, domain: &DomainRef) -> bool {
+            let in_scope = |domain: &DomainRef| match domain {
+                DomainRef::Plain(domain) => self.domains.iter().any(|d| d == domain),
+                DomainRef::Blinded(_) => false,
+            };
+            (self.profiles.is_empty() || self.profiles.iter().any(|p| p == profile))
+                && (self.domains.is_empty() || in_scope(domain))
+        }
+    }
+
+    #[ink(event)]
+    pub struct CookieRegistered {
+        cookie: String,
+        owner: AccountId,
+        block: BlockNumber,
+        transaction_id: u64,
+    }
+
+    #[ink(event)]
+    pub struct CookieUpdated {
+        cookie: String,
+        owner: AccountId,
+        block: BlockNumber,
+        transaction_id: u64,
+        changed_fields: u16,  // Bitmask of FIELD_* constants
+    }
+
+    #[ink(event)]
+    pub struct CookiesBatched {
+        owner: AccountId,
+        block: BlockNumber,
+        created: u32,
+        updated: u32,
+        first_transaction_id: u64,
+        last_transaction_id: u64,
+    }
+
+    #[ink(event)]
+    pub struct CookieDeleted {
+        cookie: String,
+        owner: AccountId,
+        block: BlockNumber,
+        transaction_id: u64,
+    }
+
+    #[ink(event)]
+    pub struct CookieShared {
+        owner: AccountId,
+        grantee: AccountId,
+        target: GrantTarget,
+        permission: Permission,
+        expires_at: Option<BlockNumber>,
+        block: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct ContentKeySet {
+        account: AccountId,
+        profile: String,
+        key_id: u32,
+        block: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct ShareRevoked {
+        owner: AccountId,
+        grantee: AccountId,
+        target: GrantTarget,
+        block: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct RoleGranted {
+        role: Role,
+        account: AccountId,
+        granted_by: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct RoleRevoked {
+        role: Role,
+        account: AccountId,
+        revoked_by: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct OwnershipTransferred {
+        previous_owner: AccountId,
+        new_owner: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct Paused {
+        by: AccountId,
+        reason: String,
+    }
+
+    #[ink(event)]
+    pub struct Unpaused {
+        by: AccountId,
+        reason: String,
+    }
+
+    #[ink(event)]
+    pub struct Upgraded {
+        code_hash: Hash,
+    }
+
+    #[ink(event)]
+    pub struct Migrated {
+        from_version: u32,
+        to_version: u32,
+    }
+
+    #[ink(event)]
+    pub struct OperatorProposed {
+        account: AccountId,
+        operator: AccountId,
+        expires_at: Option<BlockNumber>,
+    }
+
+    #[ink(event)]
+    pub struct OperatorAuthorized {
+        account: AccountId,
+        operator: AccountId,
+        expires_at: Option<BlockNumber>,
+    }
+
+    #[ink(event)]
+    pub struct OperatorRevoked {
+        account: AccountId,
+        operator: AccountId,
+    }
+
+    #[ink(event)]
+    pub struct PublicKeySet {
+        account: AccountId,
+        key_id: u32,
+        kind: KeyKind,
+        previous_key_id: Option<u32>,  // Key replaced by a rotation
+        block: BlockNumber,
+    }
+
+    #[ink(event)]
+    pub struct PublicKeyRevoked {
+        account: AccountId,
+        key_id: u32,
+        block: BlockNumber,
+    }
+
+    /// Number of revisions kept per cookie unless the owner changes it
+    pub const DEFAULT_MAX_REVISIONS: u32 = 16;
+
+    /// Number of cookies accepted per batch call unless the owner changes it
+    pub const DEFAULT_MAX_BATCH_SIZE: u32 = 64;
+
+    #[ink(storage)]
+    pub struct CookieContract {
+        owner: AccountId,
+        pending_owner: Option<AccountId>,  // Set by transfer_ownership until accepted
+        roles: Mapping<(Role, AccountId), ()>,
+        paused: bool,  // Blocks writes; reads, revocations and administration keep working
+        // Kept outside the root cell so any layout can read it; missing on storage written
+        // before versioning, which counts as version 0
+        storage_version: Lazy<u32>,
+        migration_cursor: Lazy<u32>,  // Position in cookie_list a running migration resumes at
+        prune_bucket: Lazy<u64>,  // Earliest expiry bucket that may still hold cookies
+        // Set on vaults to the registry that deployed them, which may upgrade them too
+        vault_registry: Lazy<AccountId>,
+        // First transaction with a record; earlier ones predate the transaction log
+        first_logged_transaction: Lazy<u64>,
+        operators: Mapping<AccountId, Operator>,
+        account_operators: IndexedList<AccountId, AccountId>,  // Operators per account
+        // Authorizations waiting for the operator to accept, per (account, operator)
+        pending_operators: Mapping<(AccountId, AccountId), Operator>,
+        cookie_count: u32,
+        cookies: Mapping<CookieKey, CookieEntry>,
+        cookie_list: IndexedList<(), CookieKey>,
+        unclaimed_deposits: Mapping<AccountId, Balance>,  // Deposits whose refund failed
+        expiry_index: IndexedList<u64, CookieKey>,  // Cookies with an expiry, per bucket
+        owned_cookies: IndexedList<AccountId, String>,
+        profile_cookies: IndexedList<GroupKey, String>,
+        domain_cookies: IndexedList<GroupKey, String>,
+        host_cookies: IndexedList<HostKey, String>,
+        transaction_id_counter: u64,  // Counter for transaction IDs
+        transactions: Mapping<u64, TxRecord>,
+        public_keys: Mapping<(AccountId, u32), PublicKeyRecord>,  // Key registry of users
+        public_key_count: Mapping<AccountId, u32>,  // Keys registered per user; ids start at 1
+        active_public_keys: Mapping<(AccountId, KeyKind), u32>,  // Active key id per kind
+        last_stages: Mapping<AccountId, String>,  // Last stage (profile, domain, etc.)
+        compartment_id_counter: u32,
+        compartments: Mapping<u32, Compartment>,
+        owned_compartments: IndexedList<AccountId, u32>,
+        profile_id_counter: u32,
+        profiles: Mapping<u32, Profile>,
+        compartment_profiles: IndexedList<u32, u32>,
+        domain_id_counter: u32,
+        domains: Mapping<u32, Domain>,
+        profile_domains: IndexedList<u32, u32>,
+        domain_lookup: Mapping<(AccountId, String), u32>,  // Domain name -> domain id per user
+        cookie_revisions: Mapping<(CookieKey, u32), CookieRevision>,
+        max_revisions: u32,  // Revisions retained per cookie, including the latest
+        max_batch_size: u32,
+        grants: Mapping<(GrantKey, AccountId), Grant>,  // (owner, target), grantee -> grant
+        target_grants: IndexedList<GrantKey, AccountId>,  // Grantees per shared target
+        received_grants: IndexedList<AccountId, GrantKey>,  // Shared targets per grantee
+        content_keys: Mapping<GroupKey, Envelope>,  // (owner, profile) -> wrapped content key
+    }
+
+    impl CookieContract {
+        #[ink(constructor)]
+        pub fn new() -> Self {
+            Self::new_for(Self::env().caller())
+        }
+
+        // Deploy on behalf of `owner`, as a factory does for the vault of one of its users
+        #[ink(constructor)]
+        pub fn new_for(owner: AccountId) -> Self {
+            Self::new_contract(owner)
+        }
+
+        // Deploy a private vault of `owner`: cookie listings are only readable by the owner
+        // and its operators, and the deploying registry may upgrade it
+        #[ink(constructor)]
+        pub fn new_vault(owner: AccountId) -> Self {
+            let mut contract = Self::new_contract(owner);
+            contract.vault_registry.set(&Self::env().caller());
+            contract
+        }
+
+        fn new_contract(owner: AccountId) -> Self {
+            let mut roles = Mapping::default();
+            roles.insert((Role::Admin, owner), &());
+            let mut storage_version = Lazy::default();
+            storage_version.set(&STORAGE_VERSION);
+            let mut first_logged_transaction = Lazy::default();
+            first_logged_transaction.set(&1);
+            Self {
+                owner,
+                pending_owner: None,
+                roles,
+                paused: false,
+                storage_version,
+                migration_cursor: Lazy::default(),
+                prune_bucket: Lazy::default(),
+                vault_registry: Lazy::default(),
+                first_logged_transaction,
+                operators: Mapping::default(),
+                account_operators: IndexedList::default(),
+                pending_operators: Mapping::default(),
+                cookie_count: 0,
+                cookies: Mapping::default(),
+                cookie_list: IndexedList::default(),
+                unclaimed_deposits: Mapping::default(),
+                expiry_index: IndexedList::default(),
+                owned_cookies: IndexedList::default(),
+                profile_cookies: IndexedList::default(),
+                domain_cookies: IndexedList::default(),
+                host_cookies: IndexedList::default(),
+                transaction_id_counter: 0,
+                transactions: Mapping::default(),
+                public_keys: Mapping::default(),
+                public_key_count: Mapping::default(),
+                active_public_keys: Mapping::default(),
+                last_stages: Mapping::default(),
+                compartment_id_counter: 0,
+                compartments: Mapping::default(),
+                owned_compartments: IndexedList::default(),
+                profile_id_counter: 0,
+                profiles: Mapping::default(),
+                compartment_profiles: IndexedList::default(),
+                domain_id_counter: 0,
+                domains: Mapping::default(),
+                profile_domains: IndexedList::default(),
+                domain_lookup: Mapping::default(),
+                cookie_revisions: Mapping::default(),
+                max_revisions: DEFAULT_MAX_REVISIONS,
+                max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+                grants: Mapping::default(),
+                target_grants: IndexedList::default(),
+                received_grants: IndexedList::default(),
+                content_keys: Mapping::default(),
+            }
+        }
+
+        // Register a new cookie with transaction ID; any value sent is held in escrow as its
+        // deposit
+        #[ink(message, payable)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn register_cookie(
+            &mut self,
+            profile: String,
+            cookie: String,
+            domain: DomainRef,
+            expiration_date: Vec<u8>,
+            name: Vec<u8>,
+            secure: Vec<u8>,
+            path: Vec<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let current_block = self.env().block_number();
+            self.ensure_scope(&profile, &domain)?;
+
+            // Check if cookie already exists - duplicate
+            if self.cookies.contains((owner, cookie.clone())) {
+                return Err(Error::CookieAlreadyExists);
+            }
+
+            let input = CookieInput {
+                profile,
+                cookie: cookie.clone(),
+                expiration_date,
+                name,
+                secure,
+                path,
+                value,
+                domain,
+                metadata: None,
+                envelope: None,
+                expires_at: None,
+            };
+            let deposit = self.env().transferred_value();
+            let transaction_id = self.create_entry(input, owner, current_block, deposit);
+
+            // Emit event
+            self.env().emit_event(CookieRegistered {
+                cookie,
+                owner,
+                block: current_block,
+                transaction_id,
+            });
+
+            Ok(())
+        }
+
+        // Register a cookie with typed plaintext attributes and an encrypted envelope; any
+        // value sent is held as its deposit
+        #[ink(message, payable)]
+        pub fn register_typed_cookie(
+            &mut self,
+            profile: String,
+            cookie: String,
+            domain: DomainRef,
+            metadata: CookieMetadata,
+            envelope: Envelope,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let current_block = self.env().block_number();
+            self.ensure_scope(&profile, &domain)?;
+
+            // Check if cookie already exists - duplicate
+            if self.cookies.contains((owner, cookie.clone())) {
+                return Err(Error::CookieAlreadyExists);
+            }
+
+            let input = CookieInput {
+                profile,
+                cookie: cookie.clone(),
+                expiration_date: Vec::new(),
+                name: Vec::new(),
+                secure: Vec::new(),
+                path: Vec::new(),
+                value: Vec::new(),
+                domain,
+                expires_at: metadata.expires_at,
+                metadata: Some(metadata),
+                envelope: Some(envelope),
+            };
+            input.validate(self.now())?;
+            self.ensure_key(owner, input.envelope.as_ref())?;
+            let deposit = self.env().transferred_value();
+            let transaction_id = self.create_entry(input, owner, current_block, deposit);
+
+            // Emit event
+            self.env().emit_event(CookieRegistered {
+                cookie,
+                owner,
+                block: current_block,
+                transaction_id,
+            });
+
+            Ok(())
+        }
+
+        // Incremental update of an existing cookie
+        #[ink(message)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn update_cookie(
+            &mut self,
+            profile: String,
+            cookie: String,
+            expiration_date: Vec<u8>,
+            name: Vec<u8>,
+            secure: Vec<u8>,
+            path: Vec<u8>,
+            value: Vec<u8>,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let patch = CookiePatch {
+                profile: Some(profile),
+                expiration_date: Some(expiration_date),
+                name: Some(name),
+                secure: Some(secure),
+                path: Some(path),
+                value: Some(value),
+                domain: None,
+                metadata: None,
+                envelope: None,
+                expires_at: None,
+            };
+            self.patch_entry(self.effective_owner()?, cookie, patch)
+        }
+
+        // Update only the fields present in the patch
+        #[ink(message)]
+        pub fn patch_cookie(&mut self, cookie: String, patch: CookiePatch) -> Result<(), Error> {
+            self.ensure_writable()?;
+            self.patch_entry(self.effective_owner()?, cookie, patch)
+        }
+
+        // Register the cookie if it does not exist yet, otherwise update it. Value may only
+        // be sent along when the cookie is created, and is then held as its deposit.
+        #[ink(message, payable)]
+        #[allow(clippy::too_many_arguments)]
+        pub fn upsert_cookie(
+            &mut self,
+            profile: String,
+            cookie: String,
+            domain: DomainRef,
+            expiration_date: Vec<u8>,
+            name: Vec<u8>,
+            secure: Vec<u8>,
+            path: Vec<u8>,
+            value: Vec<u8>,
+        ) -> Result<WriteOutcome, Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let current_block = self.env().block_number();
+
+            let deposit = self.env().transferred_value();
+            match self.cookies.get((owner, cookie.clone())) {
+                Some(_) if deposit > 0 => Err(Error::InvalidDeposit),
+                Some(entry) => {
+                    // Only the fields this message carries change; the expiry stays
+                    self.ensure_scope(&entry.profile, &entry.domain)?;
+                    self.ensure_scope(&profile, &domain)?;
+                    let patch = CookiePatch {
+                        profile: Some(profile),
+                        domain: Some(domain),
+                        expiration_date: Some(expiration_date),
+                        name: Some(name),
+                        secure: Some(secure),
+                        path: Some(path),
+                        value: Some(value),
+                        ..Default::default()
+                    };
+                    let (transaction_id, changed_fields) =
+                        self.write_patch(entry, patch, current_block);
+                    self.env().emit_event(CookieUpdated {
+                        cookie,
+                        owner,
+                        block: current_block,
+                        transaction_id,
+                        changed_fields,
+                    });
+
+                    Ok(WriteOutcome::Updated)
+                }
+                None => {
+                    let input = CookieInput {
+                        profile,
+                        cookie: cookie.clone(),
+                        expiration_date,
+                        name,
+                        secure,
+                        path,
+                        value,
+                        domain,
+                        metadata: None,
+                        envelope: None,
+                        expires_at: None,
+                    };
+                    self.ensure_scope(&input.profile, &input.domain)?;
+                    let transaction_id =
+                        self.create_entry(input, owner, current_block, deposit);
+                    self.env().emit_event(CookieRegistered {
+                        cookie,
+                        owner,
+                        block: current_block,
+                        transaction_id,
+                    });
+
+                    Ok(WriteOutcome::Created)
+                }
+            }
+        }
+
+        // Register many new cookies at once; fails as a whole if any of them already exists.
+        // Value sent along is split evenly into the deposits of the created cookies.
+        #[ink(message, payable)]
+        pub fn register_cookies(
+            &mut self,
+            inputs: Vec<CookieInput>,
+        ) -> Result<Vec<BatchItemResult>, Error> {
+            self.ensure_writable()?;
+            self.write_batch(inputs, false)
+        }
+
+        // Register or update many cookies at once, splitting value sent along like
+        // `register_cookies` does
+        #[ink(message, payable)]
+        pub fn upsert_cookies(
+            &mut self,
+            inputs: Vec<CookieInput>,
+        ) -> Result<Vec<BatchItemResult>, Error> {
+            self.ensure_writable()?;
+            self.write_batch(inputs, true)
+        }
+
+        // Change the maximum number of cookies per batch call (admins only)
+        #[ink(message)]
+        pub fn set_max_batch_size(&mut self, max_batch_size: u32) -> Result<(), Error> {
+            self.ensure_role(Role::Admin)?;
+            if max_batch_size == 0 {
+                return Err(Error::InvalidLimit);
+            }
+            self.max_batch_size = max_batch_size;
+            Ok(())
+        }
+
+        // Get the maximum number of cookies per batch call
+        #[ink(message)]
+        pub fn get_max_batch_size(&self) -> u32 {
+            self.max_batch_size
+        }
+
+        // Delete a cookie owned by the caller
+        #[ink(message)]
+        pub fn delete_cookie(&mut self, cookie: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let current_block = self.env().block_number();
+
+            // Check if cookie exists in the owner's namespace
+            let entry = self
+                .cookies
+                .get((owner, cookie))
+                .ok_or(Error::CookieNotFound)?;
+            self.ensure_scope(&entry.profile, &entry.domain)?;
+            self.remove_entry(entry, current_block);
+            Ok(())
+        }
+
+        // Remove up to `limit` expired cookies of any owner, paying their escrowed deposits
+        // back to the owners, and return how many were removed. Deposits that cannot be paid
+        // out are left for the owner to `claim_deposits`. The runtime's storage deposit for
+        // the freed storage is refunded to the caller, not the owners. Walks the expiry index
+        // bucket by bucket from where the previous call stopped; every cookie looked at and
+        // every bucket moved past counts against `limit`, so the weight of a call does not
+        // depend on how many cookies the contract holds.
+        #[ink(message)]
+        pub fn prune_expired(&mut self, limit: u32) -> Result<u32, Error> {
+            self.ensure_open()?;
+            if limit == 0 {
+                return Err(Error::InvalidLimit);
+            }
+            let Some(mut bucket) = self.prune_bucket.get() else {
+                return Ok(0);
+            };
+            let current_block = self.env().block_number();
+            let current_bucket = expiry_bucket(self.now());
+
+            let mut position = 0;
+            let mut pruned = 0;
+            for _ in 0..limit {
+                match self.expiry_index.get(&bucket, position) {
+                    Some(key) => match self.cookies.get(&key) {
+                        // The last cookie of the bucket moves into the freed position
+                        Some(entry) if self.has_expired(entry.expires_at) => {
+                            self.remove_entry(entry, current_block);
+                            pruned += 1;
+                        }
+                        _ => position += 1,
+                    },
+                    // Buckets before the current one only ever hold expired cookies
+                    None if bucket < current_bucket => {
+                        bucket += 1;
+                        position = 0;
+                    }
+                    None => break,
+                }
+            }
+            self.prune_bucket.set(&bucket);
+            Ok(pruned)
+        }
+
+        // Pay out the caller's deposits whose refund failed when their cookies were removed,
+        // returning the amount paid
+        #[ink(message)]
+        pub fn claim_deposits(&mut self) -> Result<Balance, Error> {
+            let caller = self.env().caller();
+            let amount = self.unclaimed_deposits.take(caller).unwrap_or(0);
+            if amount > 0 {
+                self.env()
+                    .transfer(caller, amount)
+                    .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?;
+            }
+            Ok(amount)
+        }
+
+        // Get the deposits an account can claim with `claim_deposits`
+        #[ink(message)]
+        pub fn get_unclaimed_deposits(&self, account: AccountId) -> Balance {
+            self.unclaimed_deposits.get(account).unwrap_or(0)
+        }
+
+        // Get up to `limit` cookies expiring at or after `from` and before `to` (Unix
+        // seconds), earliest bucket first, starting at `start` or at the first bucket of the
+        // range. The range may span at most MAX_EXPIRY_QUERY_BUCKETS buckets. A call looks at
+        // no more than MAX_EXPIRY_SCAN index entries and buckets moved past, so a page may
+        // come back short with a cursor to go on from.
+        #[ink(message)]
+        pub fn get_expiring_between(
+            &self,
+            from: u64,
+            to: u64,
+            start: Option<ExpiryCursor>,
+            limit: u32,
+        ) -> Result<Page<CookieKey, ExpiryCursor>, Error> {
+            if from >= to {
+                return Err(Error::InvalidRange);
+            }
+            let (first, last) = (expiry_bucket(from), expiry_bucket(to - 1));
+            if last - first >= MAX_EXPIRY_QUERY_BUCKETS {
+                return Err(Error::InvalidRange);
+            }
+            let (mut bucket, mut position) = start.unwrap_or((first, 0));
+            if !(first..=last).contains(&bucket) {
+                return Err(Error::InvalidRange);
+            }
+            if !self.can_list() {
+                return Ok(Page::default());
+            }
+
+            let limit = limit.min(MAX_PAGE_SIZE) as usize;
+            let mut keys = Vec::new();
+            for _ in 0..MAX_EXPIRY_SCAN {
+                if keys.len() >= limit {
+                    break;
+                }
+                match self.expiry_index.get(&bucket, position) {
+                    Some(key) => {
+                        position += 1;
+                        let entry = self.cookies.get(&key);
+                        let expires_at = entry.and_then(|entry| entry.expires_at);
+                        if expires_at.is_some_and(|expires_at| (from..to).contains(&expires_at)) {
+                            keys.push(key);
+                        }
+                    }
+                    None if bucket < last => {
+                        bucket += 1;
+                        position = 0;
+                    }
+                    None => return Ok(Page { items: keys, next: None }),
+                }
+            }
+            Ok(Page {
+                items: keys,
+                next: Some((bucket, position)),
+            })
+        }
+
+        // Roll a cookie back to the value and expiration of an earlier revision
+        #[ink(message)]
+        pub fn rollback_cookie(&mut self, cookie: String, revision: u32) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let target = self
+                .cookie_revisions
+                .get(((owner, cookie.clone()), revision))
+                .ok_or(Error::RevisionNotFound)?;
+
+            // The rollback itself becomes the newest revision
+            let patch = CookiePatch {
+                expiration_date: Some(target.expiration_date),
+                value: Some(target.value),
+                metadata: target.metadata,
+                envelope: target.envelope,
+                expires_at: Some(target.expires_at),
+                ..Default::default()
+            };
+            self.patch_entry(owner, cookie, patch)
+        }
+
+        // Replace the base64 text a legacy client stored in the encrypted fields of one of
+        // the caller's cookies with the raw bytes it encodes. Fails without writing anything
+        // if a field is not valid base64, e.g. because it was already decoded.
+        #[ink(message)]
+        pub fn decode_legacy_fields(&mut self, cookie: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            let entry = self
+                .cookies
+                .get((owner, cookie.clone()))
+                .ok_or(Error::CookieNotFound)?;
+
+            let patch = CookiePatch {
+                expiration_date: Some(decode_legacy(&entry.expiration_date)?),
+                name: Some(decode_legacy(&entry.name)?),
+                secure: Some(decode_legacy(&entry.secure)?),
+                path: Some(decode_legacy(&entry.path)?),
+                value: Some(decode_legacy(&entry.value)?),
+                ..Default::default()
+            };
+            self.patch_entry(owner, cookie, patch)
+        }
+
+        // Get a single retained revision of one of the caller's cookies
+        #[ink(message)]
+        pub fn get_cookie_revision(&self, cookie: String, revision: u32) -> Option<CookieRevision> {
+            let entry = self.get_cookie(cookie)?;
+            self.cookie_revisions.get((entry.key(), revision))
+        }
+
+        // Get up to `limit` retained revisions of one of the caller's cookies, oldest first,
+        // starting at `from`
+        #[ink(message)]
+        pub fn get_cookie_history(
+            &self,
+            cookie: String,
+            from: u32,
+            limit: u32,
+        ) -> Vec<CookieRevision> {
+            let Some(entry) = self.get_cookie(cookie) else {
+                return Vec::new();
+            };
+            let key = entry.key();
+
+            let start = from.max(entry.oldest_revision);
+            (start..=entry.revision)
+                .take(limit as usize)
+                .filter_map(|revision| self.cookie_revisions.get((key.clone(), revision)))
+                .collect()
+        }
+
+        // Change how many revisions are retained per cookie (admins only)
+        #[ink(message)]
+        pub fn set_max_revisions(&mut self, max_revisions: u32) -> Result<(), Error> {
+            self.ensure_role(Role::Admin)?;
+            if max_revisions == 0 {
+                return Err(Error::InvalidLimit);
+            }
+            self.max_revisions = max_revisions;
+            Ok(())
+        }
+
+        // Get how many revisions are retained per cookie
+        #[ink(message)]
+        pub fn get_max_revisions(&self) -> u32 {
+            self.max_revisions
+        }
+
+        // Store the content key of one of the caller's profiles, wrapped to one of their
+        // X25519 keys. Setting it again is meant for re-wrapping the same key, e.g. after a
+        // key rotation; envelopes sealed under a replaced key no longer open with it.
+        #[ink(message)]
+        pub fn set_content_key(
+            &mut self,
+            profile: String,
+            wrapped_key: Envelope,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let owner = self.effective_owner()?;
+            if let Some(operator) = self.operators.get(self.env().caller()) {
+                if !operator.profiles.is_empty() && !operator.profiles.contains(&profile) {
+                    return Err(Error::OutOfScope);
+                }
+            }
+            wrapped_key.validate()?;
+            self.ensure_key(owner, Some(&wrapped_key))?;
+
+            self.content_keys.insert((owner, profile.clone()), &wrapped_key);
+            self.env().emit_event(ContentKeySet {
+                account: owner,
+                profile,
+                key_id: wrapped_key.key_id,
+                block: self.env().block_number(),
+            });
+            Ok(())
+        }
+
+        // Get the wrapped content key of an account's profile
+        #[ink(message)]
+        pub fn get_content_key(&self, account: AccountId, profile: String) -> Option<Envelope> {
+            if !self.can_list() {
+                return None;
+            }
+            self.content_keys.get((account, profile))
+        }
+
+        // Share one of the caller's cookies, or all cookies of one of their profiles, with
+        // another account; sharing the same target again replaces the grant. A cookie
+        // grant wraps the cookie's key, a profile grant the profile's content key.
+        #[ink(message)]
+        pub fn share(
+            &mut self,
+            target: GrantTarget,
+            grantee: AccountId,
+            permission: Permission,
+            wrapped_key: Envelope,
+            expires_at: Option<BlockNumber>,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let caller = self.env().caller();
+            let current_block = self.env().block_number();
+
+            if grantee == caller {
+                return Err(Error::InvalidGrantee);
+            }
+            if let GrantTarget::Cookie(cookie) = &target {
+                if !self.cookies.contains((caller, cookie.clone())) {
+                    return Err(Error::CookieNotFound);
+                }
+            }
+            if expires_at.is_some_and(|expires_at| expires_at <= current_block) {
+                return Err(Error::InvalidExpiration);
+            }
+            wrapped_key.validate()?;
+            self.ensure_key(grantee, Some(&wrapped_key))?;
+
+            let key = (caller, target.clone());
+            if !self.grants.contains((key.clone(), grantee)) {
+                self.target_grants.push(&key, &grantee);
+                self.received_grants.push(&grantee, &key);
+            }
+            let grant = Grant {
+                permission,
+                wrapped_key,
+                expires_at,
+                created_at: current_block,
+            };
+            self.grants.insert((key, grantee), &grant);
+
+            self.env().emit_event(CookieShared {
+                owner: caller,
+                grantee,
+                target,
+                permission,
+                expires_at,
+                block: current_block,
+            });
+
+            Ok(())
+        }
+
+        // Withdraw a grant made by the caller
+        #[ink(message)]
+        pub fn revoke_share(
+            &mut self,
+            target: GrantTarget,
+            grantee: AccountId,
+        ) -> Result<(), Error> {
+            let key = (self.env().caller(), target);
+            if !self.grants.contains((key.clone(), grantee)) {
+                return Err(Error::GrantNotFound);
+            }
+            self.remove_grant(&key, grantee);
+            Ok(())
+        }
+
+        // Get the grant an owner made to a grantee for a target, expired or not
+        #[ink(message)]
+        pub fn get_grant(
+            &self,
+            owner: AccountId,
+            target: GrantTarget,
+            grantee: AccountId,
+        ) -> Option<Grant> {
+            self.read_grant(&(owner, target), grantee)
+        }
+
+        // Get a page of the accounts an owner shared a target with
+        #[ink(message)]
+        pub fn get_target_grants(
+            &self,
+            owner: AccountId,
+            target: GrantTarget,
+            start: u32,
+            limit: u32,
+        ) -> Page<AccountId> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.target_grants.page(&(owner, target), start, limit)
+        }
+
+        // Get a page of the targets shared with an account. Grantees of a vault only see
+        // their own.
+        #[ink(message)]
+        pub fn get_received_grants(
+            &self,
+            grantee: AccountId,
+            start: u32,
+            limit: u32,
+        ) -> Page<GrantKey> {
+            if !self.can_list() && self.env().caller() != grantee {
+                return Page::default();
+            }
+            self.received_grants.page(&grantee, start, limit)
+        }
+
+        // Get another account's cookie shared with the caller, together with the grant
+        // holding the wrapped content key
+        #[ink(message)]
+        pub fn get_shared_cookie(
+            &self,
+            owner: AccountId,
+            cookie: String,
+        ) -> Result<(CookieEntry, Grant), Error> {
+            let entry = self
+                .cookies
+                .get((owner, cookie))
+                .ok_or(Error::CookieNotFound)?;
+            let grant = self.caller_grant(&entry, Permission::Read)?;
+            Ok((entry, grant))
+        }
+
+        // Update another account's cookie the caller holds a write grant for
+        #[ink(message)]
+        pub fn patch_shared_cookie(
+            &mut self,
+            owner: AccountId,
+            cookie: String,
+            patch: CookiePatch,
+        ) -> Result<(), Error> {
+            self.ensure_open()?;
+            let entry = self
+                .cookies
+                .get((owner, cookie.clone()))
+                .ok_or(Error::CookieNotFound)?;
+            self.caller_grant(&entry, Permission::Write)?;
+            // A grant covers the cookie where it is; moving it could take it out of or into
+            // the reach of other grants and the owner's operators
+            let moves = patch.profile.as_ref().is_some_and(|profile| *profile != entry.profile)
+                || patch.domain.as_ref().is_some_and(|domain| *domain != entry.domain);
+            if moves {
+                return Err(Error::NotAuthorized);
+            }
+            self.patch_entry(owner, cookie, patch)
+        }
+
+        // Register the caller's first key of a kind; an active key is never overwritten,
+        // use rotate_public_key instead
+        #[ink(message)]
+        pub fn set_public_key(&mut self, kind: KeyKind, key: [u8; 32]) -> Result<u32, Error> {
+            self.ensure_open()?;
+            let caller = self.env().caller();
+            if self.active_public_keys.contains((caller, kind)) {
+                return Err(Error::KeyAlreadySet);
+            }
+            Ok(self.add_public_key(caller, kind, key, None))
+        }
+
+        // Replace the caller's active key of a kind; the old key stays readable by id. No
+        // signature by the old key is asked for, see PublicKeyRecord.
+        #[ink(message)]
+        pub fn rotate_public_key(&mut self, kind: KeyKind, key: [u8; 32]) -> Result<u32, Error> {
+            self.ensure_open()?;
+            let caller = self.env().caller();
+            let previous = self
+                .active_public_keys
+                .get((caller, kind))
+                .ok_or(Error::KeyNotFound)?;
+            Ok(self.add_public_key(caller, kind, key, Some(previous)))
+        }
+
+        // Revoke one of the caller's keys; a revoked active key leaves its kind unset
+        #[ink(message)]
+        pub fn revoke_public_key(&mut self, key_id: u32) -> Result<(), Error> {
+            let caller = self.env().caller();
+            let current_block = self.env().block_number();
+            let mut record = self
+                .public_keys
+                .get((caller, key_id))
+                .ok_or(Error::KeyNotFound)?;
+            if record.revoked_at.is_some() {
+                return Err(Error::KeyRevoked);
+            }
+
+            record.revoked_at = Some(current_block);
+            self.public_keys.insert((caller, key_id), &record);
+            if self.active_public_keys.get((caller, record.kind)) == Some(key_id) {
+                self.active_public_keys.remove((caller, record.kind));
+            }
+
+            self.env().emit_event(PublicKeyRevoked {
+                account: caller,
+                key_id,
+                block: current_block,
+            });
+
+            Ok(())
+        }
+
+        // Get a user's active key of a kind, including its id
+        #[ink(message)]
+        pub fn get_public_key(&self, account: AccountId, kind: KeyKind) -> Option<PublicKeyRecord> {
+            let key_id = self.active_public_keys.get((account, kind))?;
+            self.public_keys.get((account, key_id))
+        }
+
+        // Get any of a user's keys by id, including rotated and revoked ones
+        #[ink(message)]
+        pub fn get_public_key_by_id(
+            &self,
+            account: AccountId,
+            key_id: u32,
+        ) -> Option<PublicKeyRecord> {
+            self.public_keys.get((account, key_id))
+        }
+
+        // Set or update the last stage for the user (profile, domain, etc.)
+        #[ink(message)]
+        pub fn set_last_stage(&mut self, stage: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let caller = self.env().caller();
+            self.last_stages.insert(caller, &stage);
+            Ok(())
+        }
+
+        // Get the last stage associated with the user
+        #[ink(message)]
+        pub fn get_last_stage(&self) -> Option<String> {
+            let caller = self.env().caller();
+            self.last_stages.get(caller)
+        }
+
+        // Create a compartment for the caller
+        #[ink(message)]
+        pub fn create_compartment(&mut self, name: String) -> Result<u32, Error> {
+            self.ensure_writable()?;
+            let caller = self.env().caller();
+
+            self.compartment_id_counter = self.compartment_id_counter.wrapping_add(1);
+            let id = self.compartment_id_counter;
+
+            let compartment = Compartment {
+                id,
+                name,
+                owner: caller,
+                created_at: self.env().block_number(),
+            };
+            self.compartments.insert(id, &compartment);
+
+            self.owned_compartments.push(&caller, &id);
+
+            Ok(id)
+        }
+
+        // Rename one of the caller's compartments
+        #[ink(message)]
+        pub fn rename_compartment(&mut self, id: u32, name: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let mut compartment = self.caller_compartment(id)?;
+            compartment.name = name;
+            self.compartments.insert(id, &compartment);
+            Ok(())
+        }
+
+        // Remove an empty compartment of the caller
+        #[ink(message)]
+        pub fn remove_compartment(&mut self, id: u32) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let compartment = self.caller_compartment(id)?;
+
+            if self.compartment_profiles.len(&id) > 0 {
+                return Err(Error::CompartmentNotEmpty);
+            }
+
+            self.compartments.remove(id);
+            self.owned_compartments.remove(&compartment.owner, &id);
+
+            Ok(())
+        }
+
+        // Get the caller's compartments
+        #[ink(message)]
+        pub fn get_compartments(&self) -> Vec<Compartment> {
+            let caller = self.env().caller();
+            self.owned_compartments
+                .to_vec(&caller)
+                .into_iter()
+                .filter_map(|id| self.compartments.get(id))
+                .collect()
+        }
+
+        // Create a profile inside one of the caller's compartments
+        #[ink(message)]
+        pub fn create_profile(&mut self, compartment_id: u32, name: String) -> Result<u32, Error> {
+            self.ensure_writable()?;
+            let compartment = self.caller_compartment(compartment_id)?;
+
+            self.profile_id_counter = self.profile_id_counter.wrapping_add(1);
+            let id = self.profile_id_counter;
+
+            let profile = Profile {
+                id,
+                compartment_id,
+                name,
+                owner: compartment.owner,
+                created_at: self.env().block_number(),
+            };
+            self.profiles.insert(id, &profile);
+
+            self.compartment_profiles.push(&compartment_id, &id);
+
+            Ok(id)
+        }
+
+        // Rename one of the caller's profiles
+        #[ink(message)]
+        pub fn rename_profile(&mut self, id: u32, name: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let mut profile = self.caller_profile(id)?;
+            profile.name = name;
+            self.profiles.insert(id, &profile);
+            Ok(())
+        }
+
+        // Remove a profile of the caller that has no domains left
+        #[ink(message)]
+        pub fn remove_profile(&mut self, id: u32) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let profile = self.caller_profile(id)?;
+
+            if self.profile_domains.len(&id) > 0 {
+                return Err(Error::ProfileNotEmpty);
+            }
+
+            self.profiles.remove(id);
+            self.compartment_profiles.remove(&profile.compartment_id, &id);
+
+            Ok(())
+        }
+
+        // Get the profiles of one of the caller's compartments
+        #[ink(message)]
+        pub fn get_profiles(&self, compartment_id: u32) -> Result<Vec<Profile>, Error> {
+            self.caller_compartment(compartment_id)?;
+            Ok(self
+                .compartment_profiles
+                .to_vec(&compartment_id)
+                .into_iter()
+                .filter_map(|id| self.profiles.get(id))
+                .collect())
+        }
+
+        // Map a domain to one of the caller's profiles
+        #[ink(message)]
+        pub fn create_domain(&mut self, profile_id: u32, name: String) -> Result<u32, Error> {
+            self.ensure_writable()?;
+            let profile = self.caller_profile(profile_id)?;
+
+            // A domain can only load cookies from a single profile
+            if self.domain_lookup.contains((profile.owner, name.clone())) {
+                return Err(Error::DomainAlreadyExists);
+            }
+
+            self.domain_id_counter = self.domain_id_counter.wrapping_add(1);
+            let id = self.domain_id_counter;
+
+            self.domain_lookup.insert((profile.owner, name.clone()), &id);
+            let domain = Domain {
+                id,
+                profile_id,
+                name,
+                owner: profile.owner,
+                created_at: self.env().block_number(),
+            };
+            self.domains.insert(id, &domain);
+
+            self.profile_domains.push(&profile_id, &id);
+
+            Ok(id)
+        }
+
+        // Rename one of the caller's domains
+        #[ink(message)]
+        pub fn rename_domain(&mut self, id: u32, name: String) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let mut domain = self.caller_domain(id)?;
+
+            if domain.name == name {
+                return Ok(());
+            }
+            if self.domain_lookup.contains((domain.owner, name.clone())) {
+                return Err(Error::DomainAlreadyExists);
+            }
+
+            self.domain_lookup.remove((domain.owner, domain.name.clone()));
+            self.domain_lookup.insert((domain.owner, name.clone()), &id);
+            domain.name = name;
+            self.domains.insert(id, &domain);
+            Ok(())
+        }
+
+        // Remove one of the caller's domains
+        #[ink(message)]
+        pub fn remove_domain(&mut self, id: u32) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let domain = self.caller_domain(id)?;
+
+            self.domains.remove(id);
+            self.domain_lookup.remove((domain.owner, domain.name));
+            self.profile_domains.remove(&domain.profile_id, &id);
+
+            Ok(())
+        }
+
+        // Get the domains of one of the caller's profiles
+        #[ink(message)]
+        pub fn get_domains(&self, profile_id: u32) -> Result<Vec<Domain>, Error> {
+            self.caller_profile(profile_id)?;
+            Ok(self
+                .profile_domains
+                .to_vec(&profile_id)
+                .into_iter()
+                .filter_map(|id| self.domains.get(id))
+                .collect())
+        }
+
+        // Find which of the caller's domains matches a host name
+        #[ink(message)]
+        pub fn find_domain(&self, name: String) -> Option<Domain> {
+            let caller = self.env().caller();
+            self.domain_lookup
+                .get((caller, name))
+                .and_then(|id| self.domains.get(id))
+        }
+
+        // Get details of one of the caller's cookies
+        #[ink(message)]
+        pub fn get_cookie(&self, cookie: String) -> Option<CookieEntry> {
+            let entry = self.cookies.get((self.effective_owner().ok()?, cookie))?;
+            self.ensure_scope(&entry.profile, &entry.domain).ok()?;
+            Some(entry)
+        }
+
+        // Get details of one of the caller's cookies unless it has expired
+        #[ink(message)]
+        pub fn get_unexpired_cookie(&self, cookie: String) -> Option<CookieEntry> {
+            self.get_cookie(cookie)
+                .filter(|entry| !self.has_expired(entry.expires_at))
+        }
+
+        // Get details of a cookie in another account's namespace
+        #[ink(message)]
+        pub fn get_cookie_of(&self, owner: AccountId, cookie: String) -> Option<CookieEntry> {
+            if !self.can_list() {
+                return None;
+            }
+            self.cookies.get((owner, cookie))
+        }
+
+        // Get a page of all cookies
+        #[ink(message)]
+        pub fn get_all_cookies(&self, start: u32, limit: u32) -> Page<CookieKey> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.cookie_list.page(&(), start, limit)
+        }
+
+        // Get a page of the cookies owned by an account
+        #[ink(message)]
+        pub fn get_owned_cookies(
+            &self,
+            account: AccountId,
+            start: u32,
+            limit: u32,
+        ) -> Page<String> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.owned_cookies.page(&account, start, limit)
+        }
+
+        // Get a page of an account's cookies stored under a profile
+        #[ink(message)]
+        pub fn get_profile_cookies(
+            &self,
+            account: AccountId,
+            profile: String,
+            start: u32,
+            limit: u32,
+        ) -> Page<String> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.profile_cookies.page(&(account, profile), start, limit)
+        }
+
+        // Get a page of an account's cookies for a plaintext domain; blinded entries are only
+        // found through `get_cookies_for_host`
+        #[ink(message)]
+        pub fn get_domain_cookies(
+            &self,
+            account: AccountId,
+            domain: String,
+            start: u32,
+            limit: u32,
+        ) -> Page<String> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.domain_cookies.page(&(account, domain), start, limit)
+        }
+
+        // Get up to `limit` of the caller's unexpired cookies of a profile that a request to a
+        // host carries, given the `host_hash_chain` of the host: its domain hash followed by
+        // those of its parent domains. Blinded entries are found by passing the tags of the
+        // same domains instead. Host-only cookies only match the host itself. Starts at
+        // `start` or at the host, and looks at no more than MAX_HOST_SCAN index entries and
+        // domains, so a page may come back short with a cursor to go on from.
+        #[ink(message)]
+        pub fn get_cookies_for_host(
+            &self,
+            profile: String,
+            host_hash_chain: Vec<DomainHash>,
+            start: Option<HostCursor>,
+            limit: u32,
+        ) -> Result<Page<CookieEntry, HostCursor>, Error> {
+            if host_hash_chain.len() > MAX_HOST_LABELS as usize {
+                return Err(Error::InvalidLimit);
+            }
+            let (mut depth, mut position) = start.unwrap_or((0, 0));
+            if start.is_some() && depth as usize >= host_hash_chain.len() {
+                return Err(Error::InvalidRange);
+            }
+            let owner = self.effective_owner()?;
+
+            let limit = limit.min(MAX_PAGE_SIZE) as usize;
+            let mut entries = Vec::new();
+            for _ in 0..MAX_HOST_SCAN {
+                let Some(hash) = host_hash_chain.get(depth as usize) else {
+                    break;
+                };
+                if entries.len() >= limit {
+                    break;
+                }
+                let host_key = (owner, profile.clone(), *hash);
+                let Some(cookie) = self.host_cookies.get(&host_key, position) else {
+                    depth += 1;
+                    position = 0;
+                    continue;
+                };
+                position += 1;
+                let Some(entry) = self.cookies.get((owner, cookie)) else {
+                    continue;
+                };
+                let host_only = entry.metadata.as_ref().is_some_and(|metadata| metadata.host_only);
+                if (depth == 0 || !host_only)
+                    && !self.has_expired(entry.expires_at)
+                    && self.ensure_scope(&entry.profile, &entry.domain).is_ok()
+                {
+                    entries.push(entry);
+                }
+            }
+            Ok(Page {
+                items: entries,
+                next: ((depth as usize) < host_hash_chain.len()).then_some((depth, position)),
+            })
+        }
+
+        // Get total number of cookies
+        #[ink(message)]
+        pub fn get_cookie_count(&self) -> u32 {
+            self.cookie_count
+        }
+
+        // Get the record of a transaction
+        #[ink(message)]
+        pub fn get_transaction(&self, transaction_id: u64) -> Option<TxRecord> {
+            if !self.can_list() {
+                return None;
+            }
+            self.transactions.get(transaction_id)
+        }
+
+        // Get the records of up to `limit` transactions after `transaction_id`, oldest
+        // first. Pass the id of the last record seen to continue; at most MAX_PAGE_SIZE
+        // records are returned per call.
+        #[ink(message)]
+        pub fn get_transactions_since(&self, transaction_id: u64, limit: u32) -> Vec<TxRecord> {
+            let Some(first) = self.first_logged_transaction.get() else {
+                return Vec::new();
+            };
+            if !self.can_list() {
+                return Vec::new();
+            }
+            let start = transaction_id.saturating_add(1).max(first);
+            let end = start
+                .saturating_add(u64::from(limit.min(MAX_PAGE_SIZE)))
+                .min(self.transaction_id_counter.saturating_add(1));
+            (start..end)
+                .filter_map(|transaction_id| self.transactions.get(transaction_id))
+                .collect()
+        }
+
+        // Whether this contract is a private vault
+        #[ink(message)]
+        pub fn is_vault(&self) -> bool {
+            self.vault_registry.get().is_some()
+        }
+
+        // Get contract owner
+        #[ink(message)]
+        pub fn get_owner(&self) -> AccountId {
+            self.owner
+        }
+
+        // Propose a new contract owner, who takes over once they accept (contract owner
+        // only); proposing again replaces the pending owner
+        #[ink(message)]
+        pub fn transfer_ownership(&mut self, new_owner: AccountId) -> Result<(), Error> {
+            self.ensure_owner()?;
+            self.pending_owner = Some(new_owner);
+            Ok(())
+        }
+
+        // Become the contract owner after being proposed; the admin role moves along
+        #[ink(message)]
+        pub fn accept_ownership(&mut self) -> Result<(), Error> {
+            let caller = self.env().caller();
+            if self.pending_owner != Some(caller) {
+                return Err(Error::NotAuthorized);
+            }
+
+            let previous_owner = self.owner;
+            self.owner = caller;
+            self.pending_owner = None;
+            self.roles.remove((Role::Admin, previous_owner));
+            self.roles.insert((Role::Admin, caller), &());
+
+            self.env().emit_event(OwnershipTransferred {
+                previous_owner,
+                new_owner: caller,
+            });
+
+            Ok(())
+        }
+
+        // Get the account proposed as the next contract owner
+        #[ink(message)]
+        pub fn get_pending_owner(&self) -> Option<AccountId> {
+            self.pending_owner
+        }
+
+        // Give an account a contract-wide role (admins only)
+        #[ink(message)]
+        pub fn grant_role(&mut self, role: Role, account: AccountId) -> Result<(), Error> {
+            self.ensure_role(Role::Admin)?;
+            if matches!(role, Role::Operator | Role::Owner) {
+                return Err(Error::InvalidRole);
+            }
+            self.roles.insert((role, account), &());
+            self.env().emit_event(RoleGranted {
+                role,
+                account,
+                granted_by: self.env().caller(),
+            });
+            Ok(())
+        }
+
+        // Take a contract-wide role away (admins only); the owner always stays admin
+        #[ink(message)]
+        pub fn revoke_role(&mut self, role: Role, account: AccountId) -> Result<(), Error> {
+            self.ensure_role(Role::Admin)?;
+            if matches!(role, Role::Operator | Role::Owner) {
+                return Err(Error::InvalidRole);
+            }
+            if role == Role::Admin && account == self.owner {
+                return Err(Error::NotAuthorized);
+            }
+            self.roles.remove((role, account));
+            self.env().emit_event(RoleRevoked {
+                role,
+                account,
+                revoked_by: self.env().caller(),
+            });
+            Ok(())
+        }
+
+        // Replace the contract code, keeping its storage (contract owner, or only the
+        // registry for a vault). Writes stay blocked until `migrate` has brought the storage
+        // up to the new code's version.
+        #[ink(message)]
+        pub fn upgrade(&mut self, code_hash: Hash) -> Result<(), Error> {
+            // A vault only takes code from its registry, which records what each vault runs
+            match self.vault_registry.get() {
+                Some(registry) if registry != self.env().caller() => {
+                    return Err(Error::NotAuthorized)
+                }
+                Some(_) => {}
+                None => self.ensure_owner()?,
+            }
+            self.env()
+                .set_code_hash(&code_hash)
+                .map_err(|error| Error::InkEnvError(format!("{:?}", error)))?;
+            self.env().emit_event(Upgraded { code_hash });
+            Ok(())
+        }
+
+        // Migrate up to `limit` cookies to the current storage version (contract owner
+        // only). Returns the position the next call resumes at, or None once storage is
+        // up to date.
+        #[ink(message)]
+        pub fn migrate(&mut self, limit: u32) -> Result<Option<u32>, Error> {
+            self.ensure_owner()?;
+            let from_version = self.get_storage_version();
+            if from_version >= STORAGE_VERSION {
+                return Ok(None);
+            }
+            if limit == 0 {
+                return Err(Error::InvalidLimit);
+            }
+            // Transactions are logged from storage version 5 on
+            if self.first_logged_transaction.get().is_none() {
+                let first = self.transaction_id_counter.wrapping_add(1);
+                self.first_logged_transaction.set(&first);
+            }
+
+            let start = self.migration_cursor.get().unwrap_or(0);
+            let page = self.cookie_list.page(&(), start, limit);
+            for key in page.items {
+                self.migrate_entry(&key, from_version)?;
+            }
+            if let Some(next) = page.next {
+                self.migration_cursor.set(&next);
+                return Ok(Some(next));
+            }
+
+            self.migration_cursor.set(&0);
+            self.storage_version.set(&STORAGE_VERSION);
+            self.env().emit_event(Migrated {
+                from_version,
+                to_version: STORAGE_VERSION,
+            });
+            Ok(None)
+        }
+
+        // Get the layout version of the contract's storage
+        #[ink(message)]
+        pub fn get_storage_version(&self) -> u32 {
+            self.storage_version.get().unwrap_or(0)
+        }
+
+        // Stop all cookie, key, sharing and hierarchy writes (contract owner or pausers only)
+        #[ink(message)]
+        pub fn pause(&mut self, reason: String) -> Result<(), Error> {
+            self.ensure_pauser()?;
+            self.ensure_open()?;
+            self.paused = true;
+            self.env().emit_event(Paused {
+                by: self.env().caller(),
+                reason,
+            });
+            Ok(())
+        }
+
+        // Allow writes again (contract owner or pausers only)
+        #[ink(message)]
+        pub fn unpause(&mut self, reason: String) -> Result<(), Error> {
+            self.ensure_pauser()?;
+            if !self.paused {
+                return Err(Error::NotPaused);
+            }
+            self.paused = false;
+            self.env().emit_event(Unpaused {
+                by: self.env().caller(),
+                reason,
+            });
+            Ok(())
+        }
+
+        // Whether writes are currently paused
+        #[ink(message)]
+        pub fn is_paused(&self) -> bool {
+            self.paused
+        }
+
+        // Whether an account holds a contract-wide role, or is some account's operator
+        #[ink(message)]
+        pub fn has_role(&self, role: Role, account: AccountId) -> bool {
+            match role {
+                Role::Operator => self
+                    .operators
+                    .get(account)
+                    .is_some_and(|operator| !self.is_expired(&operator)),
+                Role::Owner => account == self.owner,
+                _ => self.roles.contains((role, account)),
+            }
+        }
+
+        // Let another account, e.g. a device key, read and write the caller's cookies of
+        // the given profiles and domains as if it were the caller, until `expires_at`. The
+        // operator only starts acting for the caller once it calls `accept_operator`.
+        // Authorizing an accepted operator again replaces its scope and expiry.
+        #[ink(message)]
+        pub fn authorize_operator(
+            &mut self,
+            operator: AccountId,
+            profiles: Vec<String>,
+            domains: Vec<String>,
+            expires_at: Option<BlockNumber>,
+        ) -> Result<(), Error> {
+            self.ensure_writable()?;
+            let caller = self.env().caller();
+            let current_block = self.env().block_number();
+
+            if operator == caller || self.operators.contains(caller) {
+                return Err(Error::InvalidGrantee);
+            }
+            let previous = self.operators.get(operator);
+            if previous.as_ref().is_some_and(|previous| previous.account != caller) {
+                return Err(Error::NotAuthorized);
+            }
+            if profiles.len() > MAX_OPERATOR_SCOPE as usize
+                || domains.len() > MAX_OPERATOR_SCOPE as usize
+            {
+                return Err(Error::InvalidLimit);
+            }
+            if expires_at.is_some_and(|expires_at| expires_at <= current_block) {
+                return Err(Error::InvalidExpiration);
+            }
+
+            let record = Operator {
+                account: caller,
+                profiles,
+                domains,
+                expires_at,
+                created_at: current_block,
+            };
+            if previous.is_some() {
+                self.operators.insert(operator, &record);
+                self.env().emit_event(OperatorAuthorized {
+                    account: caller,
+                    operator,
+                    expires_at,
+                });
+            } else {
+                self.pending_operators.insert((caller, operator), &record);
+                self.env().emit_event(OperatorProposed {
+                    account: caller,
+                    operator,
+                    expires_at,
+                });
+            }
+
+            Ok(())
+        }
+
+        // Start acting as operator of an account that authorized the caller; an account can
+        // only operate for one other account at a time
+        #[ink(message)]
+        pub fn accept_operator(&mut self, account: AccountId) -> Result<(), Error> {
+            self.ensure_open()?;
+            let caller = self.env().caller();
+            let record = self
+                .pending_operators
+                .get((account, caller))
+                .ok_or(Error::NotAuthorized)?;
+            if self.operators.contains(caller) || self.operators.contains(account) {
+                return Err(Error::InvalidGrantee);
+            }
+            if self.is_expired(&record) {
+                return Err(Error::InvalidExpiration);
+            }
+
+            self.pending_operators.remove((account, caller));
+            self.operators.insert(caller, &record);
+            self.account_operators.push(&account, &caller);
+
+            self.env().emit_event(OperatorAuthorized {
+                account,
+                operator: caller,
+                expires_at: record.expires_at,
+            });
+
+            Ok(())
+        }
+
+        // Withdraw an operator authorized by the caller, accepted or not
+        #[ink(message)]
+        pub fn revoke_operator(&mut self, operator: AccountId) -> Result<(), Error> {
+            let caller = self.env().caller();
+            if self.pending_operators.contains((caller, operator)) {
+                self.pending_operators.remove((caller, operator));
+                return Ok(());
+            }
+            if self.operators.get(operator).map(|operator| operator.account) != Some(caller) {
+                return Err(Error::NotAuthorized);
+            }
+            self.remove_operator(caller, operator);
+            Ok(())
+        }
+
+        // Stop acting as operator of another account
+        #[ink(message)]
+        pub fn resign_operator(&mut self) -> Result<(), Error> {
+            let caller = self.env().caller();
+            let record = self.operators.get(caller).ok_or(Error::MissingRole(Role::Operator))?;
+            self.remove_operator(record.account, caller);
+            Ok(())
+        }
+
+        // Get an authorization the operator has not accepted yet
+        #[ink(message)]
+        pub fn get_pending_operator(
+            &self,
+            account: AccountId,
+            operator: AccountId,
+        ) -> Option<Operator> {
+            self.pending_operators.get((account, operator))
+        }
+
+        // Get an operator's account, scope and expiry
+        #[ink(message)]
+        pub fn get_operator(&self, operator: AccountId) -> Option<Operator> {
+            self.operators.get(operator)
+        }
+
+        // Get a page of the operators an account authorized, expired ones included
+        #[ink(message)]
+        pub fn get_operators(&self, account: AccountId, start: u32, limit: u32) -> Page<AccountId> {
+            if !self.can_list() {
+                return Page::default();
+            }
+            self.account_operators.page(&account, start, limit)
+        }
+
+        // Drop an accepted operator and its index entry
+        fn remove_operator(&mut self, account: AccountId, operator: AccountId) {
+            self.operators.remove(operator);
+            self.account_operators.remove(&account, &operator);
+            self.env().emit_event(OperatorRevoked { account, operator });
+        }
+
+        // Account whose cookies the caller acts on: the account that authorized it as
+        // operator, or the caller itself
+        fn effective_owner(&self) -> Result<AccountId, Error> {
+            let caller = self.env().caller();
+            match self.operators.get(caller) {
+                Some(operator) if self.is_expired(&operator) => {
+                    Err(Error::MissingRole(Role::Operator))
+                }
+                Some(operator) => Ok(operator.account),
+                None => Ok(caller),
+            }
+        }
+
+        // Make sure an operator caller may touch cookies of this profile and domain
+        fn ensure_scope(&self, profile: &str, domain: &DomainRef) -> Result<(), Error> {
+            match self.operators.get(self.env().caller()) {
+                Some(operator) if !operator.allows(profile, domain) => Err(Error::OutOfScope),
+                _ => Ok(()),
+            }
+        }
+
+        // Listings of a vault are only readable by its owner and the owner's operators
+        fn can_list(&self) -> bool {
+            !self.is_vault() || self.effective_owner() == Ok(self.owner)
+        }
+
+        // Cookie expiries are Unix seconds, block timestamps milliseconds
+        fn now(&self) -> u64 {
+            self.env().block_timestamp() / 1000
+        }
+
+        fn has_expired(&self, expires_at: Option<u64>) -> bool {
+            expires_at.is_some_and(|expires_at| self.now() >= expires_at)
+        }
+
+        fn is_expired(&self, operator: &Operator) -> bool {
+            operator
+                .expires_at
+                .is_some_and(|expires_at| self.env().block_number() >= expires_at)
+        }
+
+        // Only the owner and the owner's operators write to a vault
+        fn ensure_writable(&self) -> Result<(), Error> {
+            self.ensure_open()?;
+            if self.is_vault() && self.effective_owner()? != self.owner {
+                return Err(Error::NotAuthorized);
+            }
+            Ok(())
+        }
+
+        // Checks of writes any account may make to a vault too, e.g. keys and grants of
+        // the caller's own
+        fn ensure_open(&self) -> Result<(), Error> {
+            if self.paused {
+                return Err(Error::Paused);
+            }
+            if self.get_storage_version() != STORAGE_VERSION {
+                return Err(Error::MigrationPending);
+            }
+            Ok(())
+        }
+
+        // Bring one entry, its revisions and the grants on it from storage version
+        // `from_version` up to STORAGE_VERSION. Fails if any of them does not decode.
+        fn migrate_entry(&mut self, key: &CookieKey, from_version: u32) -> Result<(), Error> {
+            let failed = || Error::MigrationFailed(key.clone());
+            let mut entry = read_raw(self.cookies.key(), key)
+                .and_then(|bytes| upgrade_entry(bytes, from_version).ok())
+                .ok_or_else(failed)?;
+            for revision in entry.oldest_revision..=entry.revision {
+                let Some(bytes) = read_raw(self.cookie_revisions.key(), (key, revision)) else {
+                    continue;
+                };
+                let snapshot = upgrade_revision(bytes, from_version).map_err(|_| failed())?;
+                self.cookie_revisions.insert((key, revision), &snapshot);
+            }
+            if from_version < 6 {
+                for target in [
+                    GrantTarget::Cookie(entry.cookie.clone()),
+                    GrantTarget::Profile(entry.profile.clone()),
+                ] {
+                    let grant_key = (entry.owner, target);
+                    for grantee in self.target_grants.to_vec(&grant_key) {
+                        let grant = self.read_grant(&grant_key, grantee).ok_or_else(failed)?;
+                        self.grants.insert((&grant_key, grantee), &grant);
+                    }
+                }
+            }
+            if from_version < 2 {
+                // Typed cookies already carried their expiry in the metadata
+                entry.expires_at = entry.metadata.as_ref().and_then(|metadata| metadata.expires_at);
+                self.reindex_expiry(key, None, entry.expires_at);
+            }
+            if from_version < 1 {
+                for field in [
+                    &mut entry.expiration_date,
+                    &mut entry.name,
+                    &mut entry.secure,
+                    &mut entry.path,
+                    &mut entry.value,
+                ] {
+                    if let Ok(decoded) = decode_legacy(field) {
+                        *field = decoded;
+                    }
+                }
+                for revision in entry.oldest_revision..=entry.revision {
+                    let Some(mut snapshot) = self.cookie_revisions.get((key, revision)) else {
+                        continue;
+                    };
+                    for field in [&mut snapshot.expiration_date, &mut snapshot.value] {
+                        if let Ok(decoded) = decode_legacy(field) {
+                            *field = decoded;
+                        }
+                    }
+                    self.cookie_revisions.insert((key, revision), &snapshot);
+                }
+            }
+            if from_version < 3 {
+                let host_key = (entry.owner, entry.profile.clone(), entry.domain.index_hash());
+                if !self.host_cookies.contains(&host_key, &entry.cookie) {
+                    self.host_cookies.push(&host_key, &entry.cookie);
+                }
+            }
+            self.cookies.insert(key, &entry);
+            Ok(())
+        }
+
+        fn ensure_owner(&self) -> Result<(), Error> {
+            if self.env().caller() != self.owner {
+                return Err(Error::MissingRole(Role::Owner));
+            }
+            Ok(())
+        }
+
+        fn ensure_pauser(&self) -> Result<(), Error> {
+            if self.env().caller() == self.owner {
+                return Ok(());
+            }
+            self.ensure_role(Role::Pauser)
+        }
+
+        fn ensure_role(&self, role: Role) -> Result<(), Error> {
+            if !self.roles.contains((role, self.env().caller())) {
+                return Err(Error::MissingRole(role));
+            }
+            Ok(())
+        }
+
+        // Shared write path of update_cookie, patch_cookie, rollback_cookie and
+        // patch_shared_cookie
+        fn patch_entry(
+            &mut self,
+            owner: AccountId,
+            cookie: String,
+            patch: CookiePatch,
+        ) -> Result<(), Error> {
+            let current_block = self.env().block_number();
+
+            // Check if cookie exists in the owner's namespace
+            let entry = self
+                .cookies
+                .get((owner, cookie.clone()))
+                .ok_or(Error::CookieNotFound)?;
+            patch.validate(self.now())?;
+            self.ensure_key(owner, patch.envelope.as_ref())?;
+
+            // Operators may neither touch nor move cookies outside their scope
+            self.ensure_scope(&entry.profile, &entry.domain)?;
+            let profile = patch.profile.as_ref().unwrap_or(&entry.profile);
+            let domain = patch.domain.as_ref().unwrap_or(&entry.domain);
+            self.ensure_scope(profile, domain)?;
+
+            let (transaction_id, changed_fields) = self.write_patch(entry, patch, current_block);
+
+            // Emit event
+            self.env().emit_event(CookieUpdated {
+                cookie,
+                owner,
+                block: current_block,
+                transaction_id,
+                changed_fields,
+            });
+
+            Ok(())
+        }
+
+        // Shared write path of register_cookies and upsert_cookies
+        fn write_batch(
+            &mut self,
+            inputs: Vec<CookieInput>,
+            allow_updates: bool,
+        ) -> Result<Vec<BatchItemResult>, Error> {
+            let owner = self.effective_owner()?;
+            let current_block = self.env().block_number();
+
+            if inputs.len() > self.max_batch_size as usize {
+                return Err(Error::BatchTooLarge);
+            }
+
+            // Validate every item before touching storage so the batch is all-or-nothing
+            let now = self.now();
+            let mut existing = Vec::with_capacity(inputs.len());
+            for (index, input) in inputs.iter().enumerate() {
+                let failed = |error| Error::BatchItemFailed(index as u32, Box::new(error));
+                if inputs[..index].iter().any(|prev| prev.cookie == input.cookie) {
+                    return Err(failed(Error::DuplicateCookie));
+                }
+                let entry = self.cookies.get((owner, input.cookie.clone()));
+                input.validate(now).map_err(failed)?;
+                self.ensure_key(owner, input.envelope.as_ref()).map_err(failed)?;
+                self.ensure_scope(&input.profile, &input.domain).map_err(failed)?;
+                if let Some(entry) = &entry {
+                    self.ensure_scope(&entry.profile, &entry.domain).map_err(failed)?;
+                    if !allow_updates {
+                        return Err(failed(Error::CookieAlreadyExists));
+                    }
+                }
+                existing.push(entry);
+            }
+
+            // The first created cookie also takes what does not split evenly
+            let created = existing.iter().filter(|entry| entry.is_none()).count() as Balance;
+            let transferred = self.env().transferred_value();
+            if created == 0 && transferred > 0 {
+                return Err(Error::InvalidDeposit);
+            }
+            let share = transferred.checked_div(created).unwrap_or(0);
+            let mut remainder = transferred.checked_rem(created).unwrap_or(0);
+
+            let mut results = Vec::with_capacity(inputs.len());
+            for (input, entry) in inputs.into_iter().zip(existing) {
+                let cookie = input.cookie.clone();
+                let (outcome, transaction_id) = match entry {
+                    Some(entry) => {
+                        let (transaction_id, _) =
+                            self.write_patch(entry, input.into(), current_block);
+                        (WriteOutcome::Updated, transaction_id)
+                    }
+                    None => {
+                        let deposit = share + core::mem::take(&mut remainder);
+                        let transaction_id =
+                            self.create_entry(input, owner, current_block, deposit);
+                        (WriteOutcome::Created, transaction_id)
+                    }
+                };
+                results.push(BatchItemResult {
+                    cookie,
+                    outcome,
+                    transaction_id,
+                });
+            }
+
+            // One event for the whole batch
+            if let (Some(first), Some(last)) = (results.first(), results.last()) {
+                let created = results
+                    .iter()
+                    .filter(|result| result.outcome == WriteOutcome::Created)
+                    .count() as u32;
+                self.env().emit_event(CookiesBatched {
+                    owner,
+                    block: current_block,
+                    created,
+                    updated: results.len() as u32 - created,
+                    first_transaction_id: first.transaction_id,
+                    last_transaction_id: last.transaction_id,
+                });
+            }
+
+            Ok(results)
+        }
+
+        // Store a new entry for the caller, returning its transaction ID
+        fn create_entry(
+            &mut self,
+            input: CookieInput,
+            owner: AccountId,
+            block: BlockNumber,
+            deposit: Balance,
+        ) -> u64 {
+            // Generate transaction ID for the current operation
+            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
+            let transaction_id = self.transaction_id_counter;
+
+            let cookie = input.cookie;
+            let mut cookie_entry = CookieEntry {
+                profile: input.profile,
+                cookie: cookie.clone(),
+                domain: input.domain,
+                expiration_date: input.expiration_date,
+                name: input.name,
+                secure: input.secure,
+                path: input.path,
+                value: input.value,
+                metadata: input.metadata,
+                envelope: input.envelope,
+                created_at: block,
+                owner,
+                transaction_id,
+                revision: 0,
+                oldest_revision: 0,
+                expires_at: input.expires_at,
+                deposit,
+            };
+
+            // Update storage
+            self.record_revision(&mut cookie_entry, block);
+            let key = cookie_entry.key();
+            self.cookies.insert(&key, &cookie_entry);
+            self.log_transaction(transaction_id, TxKind::Register, &cookie_entry, block);
+            self.cookie_list.push(&(), &key);
+            self.reindex_expiry(&key, None, cookie_entry.expires_at);
+            self.cookie_count = self.cookie_count.wrapping_add(1);
+
+            // Update owned, profile and domain indexes
+            self.owned_cookies.push(&owner, &cookie);
+            let host_key = (owner, cookie_entry.profile.clone(), cookie_entry.domain.index_hash());
+            self.host_cookies.push(&host_key, &cookie);
+            self.profile_cookies.push(&(owner, cookie_entry.profile), &cookie);
+            if let DomainRef::Plain(domain) = cookie_entry.domain {
+                self.domain_cookies.push(&(owner, domain), &cookie);
+            }
+
+            transaction_id
+        }
+
+        // Remove an entry with its revisions, index entries and grants, and pay its escrowed
+        // deposit back to the owner
+        fn remove_entry(&mut self, entry: CookieEntry, block: BlockNumber) {
+            let key = entry.key();
+            let (owner, cookie) = key.clone();
+
+            // Deletions are transactions too
+            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
+            let transaction_id = self.transaction_id_counter;
+
+            // Update storage
+            self.log_transaction(transaction_id, TxKind::Delete, &entry, block);
+            for revision in entry.oldest_revision..=entry.revision {
+                self.cookie_revisions.remove((key.clone(), revision));
+            }
+            self.cookies.remove(&key);
+            self.cookie_list.remove(&(), &key);
+            self.reindex_expiry(&key, entry.expires_at, None);
+            self.cookie_count = self.cookie_count.wrapping_sub(1);
+
+            // Update owned, profile and domain indexes
+            self.owned_cookies.remove(&owner, &cookie);
+            let host_key = (owner, entry.profile.clone(), entry.domain.index_hash());
+            self.host_cookies.remove(&host_key, &cookie);
+            self.profile_cookies.remove(&(owner, entry.profile), &cookie);
+            if let DomainRef::Plain(domain) = entry.domain {
+                self.domain_cookies.remove(&(owner, domain), &cookie);
+            }
+
+            // Grants on the cookie must not carry over to a later cookie of the same name
+            let target = (owner, GrantTarget::Cookie(cookie.clone()));
+            for grantee in self.target_grants.to_vec(&target) {
+                self.remove_grant(&target, grantee);
+            }
+
+            // A failed refund, e.g. to a reaped account, must not make the removal fail or
+            // pruning could never get past the cookie
+            if entry.deposit > 0 && self.env().transfer(owner, entry.deposit).is_err() {
+                let unclaimed = self.unclaimed_deposits.get(owner).unwrap_or(0);
+                self.unclaimed_deposits.insert(owner, &unclaimed.saturating_add(entry.deposit));
+            }
+
+            // Emit event
+            self.env().emit_event(CookieDeleted {
+                cookie,
+                owner,
+                block,
+                transaction_id,
+            });
+        }
+
+        // Apply a patch to an existing entry, returning the transaction ID and changed fields
+        fn write_patch(
+            &mut self,
+            mut entry: CookieEntry,
+            patch: CookiePatch,
+            block: BlockNumber,
+        ) -> (u64, u16) {
+            // Increment transaction ID for each update
+            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
+            let transaction_id = self.transaction_id_counter;
+
+            let previous_profile = entry.profile.clone();
+            let previous_domain = entry.domain.clone();
+            let previous_expires_at = entry.expires_at;
+            let changed_fields = patch.apply(&mut entry);
+            entry.transaction_id = transaction_id;
+
+            // Move the cookie between profile/domain indexes when those fields change
+            let owner = entry.owner;
+            if changed_fields & (FIELD_PROFILE | FIELD_DOMAIN) != 0 {
+                let previous = (owner, previous_profile.clone(), previous_domain.index_hash());
+                let current = (owner, entry.profile.clone(), entry.domain.index_hash());
+                self.host_cookies.remove(&previous, &entry.cookie);
+                self.host_cookies.push(&current, &entry.cookie);
+            }
+            if changed_fields & FIELD_PROFILE != 0 {
+                let profile = entry.profile.clone();
+                self.profile_cookies.remove(&(owner, previous_profile), &entry.cookie);
+                self.profile_cookies.push(&(owner, profile), &entry.cookie);
+            }
+            if changed_fields & FIELD_DOMAIN != 0 {
+                if let DomainRef::Plain(domain) = previous_domain {
+                    self.domain_cookies.remove(&(owner, domain), &entry.cookie);
+                }
+                if let DomainRef::Plain(domain) = entry.domain.clone() {
+                    self.domain_cookies.push(&(owner, domain), &entry.cookie);
+                }
+            }
+            if changed_fields & FIELD_EXPIRES_AT != 0 {
+                self.reindex_expiry(&entry.key(), previous_expires_at, entry.expires_at);
+            }
+            entry.revision = entry.revision.wrapping_add(1);
+
+            // Update storage
+            self.record_revision(&mut entry, block);
+            self.cookies.insert(entry.key(), &entry);
+            self.log_transaction(transaction_id, TxKind::Update, &entry, block);
+
+            (transaction_id, changed_fields)
+        }
+
+        // Record the transaction that just wrote or removed an entry
+        fn log_transaction(
+            &mut self,
+            transaction_id: u64,
+            kind: TxKind,
+            entry: &CookieEntry,
+            block: BlockNumber,
+        ) {
+            let mut content_hash = [0; 32];
+            ink::env::hash_encoded::<ink::env::hash::Blake2x256, _>(entry, &mut content_hash);
+            let record = TxRecord {
+                transaction_id,
+                kind,
+                cookie: entry.key(),
+                caller: self.env().caller(),
+                block,
+                content_hash,
+            };
+            self.transactions.insert(transaction_id, &record);
+        }
+
+        // Move a cookie between buckets of the expiry index when its expiry changes
+        fn reindex_expiry(
+            &mut self,
+            key: &CookieKey,
+            previous: Option<u64>,
+            current: Option<u64>,
+        ) {
+            if let Some(previous) = previous {
+                self.expiry_index.remove(&expiry_bucket(previous), key);
+            }
+            if let Some(current) = current {
+                let bucket = expiry_bucket(current);
+                self.expiry_index.push(&bucket, key);
+                // Expiries long past, which migrated cookies may carry, must not send pruning
+                // back over years of empty buckets
+                let floor = expiry_bucket(self.now()).saturating_sub(PRUNE_GRACE_BUCKETS);
+                let start = bucket.max(floor);
+                if self.prune_bucket.get().is_none_or(|first| start < first) {
+                    self.prune_bucket.set(&start);
+                }
+            }
+        }
+
+        // Find a live grant giving the caller at least `permission` on the entry, either on
+        // the cookie itself or on its profile
+        fn caller_grant(
+            &self,
+            entry: &CookieEntry,
+            permission: Permission,
+        ) -> Result<Grant, Error> {
+            let caller = self.effective_owner()?;
+            let current_block = self.env().block_number();
+            [
+                GrantTarget::Cookie(entry.cookie.clone()),
+                GrantTarget::Profile(entry.profile.clone()),
+            ]
+            .into_iter()
+            .filter_map(|target| self.read_grant(&(entry.owner, target), caller))
+            .find(|grant| {
+                grant.permission >= permission
+                    && grant.expires_at.is_none_or(|expires_at| current_block < expires_at)
+            })
+            .ok_or(Error::NotAuthorized)
+        }
+
+        // Read a grant, whichever layout it is stored in
+        fn read_grant(&self, key: &GrantKey, grantee: AccountId) -> Option<Grant> {
+            decode_grant(&read_raw(self.grants.key(), (key, grantee))?)
+        }
+
+        // Drop a grant and its index entries
+        fn remove_grant(&mut self, key: &GrantKey, grantee: AccountId) {
+            self.grants.remove((key.clone(), grantee));
+            self.target_grants.remove(key, &grantee);
+            self.received_grants.remove(&grantee, key);
+
+            self.env().emit_event(ShareRevoked {
+                owner: key.0,
+                grantee,
+                target: key.1.clone(),
+                block: self.env().block_number(),
+            });
+        }
+
+        // Store a new key for the account, make it the active one of its kind and return
+        // its id
+        fn add_public_key(
+            &mut self,
+            account: AccountId,
+            kind: KeyKind,
+            key: [u8; 32],
+            previous_key_id: Option<u32>,
+        ) -> u32 {
+            let current_block = self.env().block_number();
+            let key_id = self.public_key_count.get(account).unwrap_or(0).wrapping_add(1);
+            let record = PublicKeyRecord {
+                key_id,
+                kind,
+                key,
+                created_at: current_block,
+                revoked_at: None,
+            };
+            self.public_keys.insert((account, key_id), &record);
+            self.public_key_count.insert(account, &key_id);
+            self.active_public_keys.insert((account, kind), &key_id);
+
+            self.env().emit_event(PublicKeySet {
+                account,
+                key_id,
+                kind,
+                previous_key_id,
+                block: current_block,
+            });
+
+            key_id
+        }
+
+        // Make sure an envelope was sealed to one of the owner's unrevoked X25519 keys
+        fn ensure_key(&self, owner: AccountId, envelope: Option<&Envelope>) -> Result<(), Error> {
+            let Some(envelope) = envelope else {
+                return Ok(());
+            };
+            let record = self
+                .public_keys
+                .get((owner, envelope.key_id))
+                .ok_or(Error::KeyNotFound)?;
+            if record.kind != KeyKind::X25519 {
+                return Err(Error::InvalidKey);
+            }
+            if record.revoked_at.is_some() {
+                return Err(Error::KeyRevoked);
+            }
+            Ok(())
+        }
+
+        // Snapshot the entry as its latest revision and drop the ones beyond the cap
+        fn record_revision(&mut self, entry: &mut CookieEntry, block: BlockNumber) {
+            let revision = CookieRevision {
+                revision: entry.revision,
+                value: entry.value.clone(),
+                expiration_date: entry.expiration_date.clone(),
+                metadata: entry.metadata.clone(),
+                envelope: entry.envelope.clone(),
+                transaction_id: entry.transaction_id,
+                block,
+                expires_at: entry.expires_at,
+            };
+            self.cookie_revisions.insert((entry.key(), entry.revision), &revision);
+
+            while entry.revision.wrapping_sub(entry.oldest_revision) >= self.max_revisions {
+                self.cookie_revisions.remove((entry.key(), entry.oldest_revision));
+                entry.oldest_revision = entry.oldest_revision.wrapping_add(1);
+            }
+        }
+
+        // Load a compartment, making sure the caller owns it
+        fn caller_compartment(&self, id: u32) -> Result<Compartment, Error> {
+            let compartment = self.compartments.get(id).ok_or(Error::CompartmentNotFound)?;
+            if compartment.owner != self.env().caller() {
+                return Err(Error::NotAuthorized);
+            }
+            Ok(compartment)
+        }
+
+        // Load a profile, making sure the caller owns it
+        fn caller_profile(&self, id: u32) -> Result<Profile, Error> {
+            let profile = self.profiles.get(id).ok_or(Error::ProfileNotFound)?;
+            if profile.owner != self.env().caller() {
+                return Err(Error::NotAuthorized);
+            }
+            Ok(profile)
+        }
+
+        // Load a domain, making sure the caller owns it
+        fn caller_domain(&self, id: u32) -> Result<Domain, Error> {
+            let domain = self.domains.get(id).ok_or(Error::DomainNotFound)?;
+            if domain.owner != self.env().caller() {
+                return Err(Error::NotAuthorized);
+            }
+            Ok(domain)
+        }
+    }
+
+    //***************TEST CASES***************//
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        type Event = <CookieContract as ink::reflect::ContractEventBase>::Type;
+
+        #[ink::test]
+        fn register_cookie_works() {
+            let mut contract = CookieContract::new();
+            assert_eq!(
+                contract.register_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    plain("example.com"),
+                    b"2024-12-31".to_vec(),
+                    b"name1".to_vec(),
+                    b"secure1".to_vec(),
+                    b"/path1".to_vec(),
+                    b"value1".to_vec(),
+                ),
+                Ok(())
+            );
+            assert_eq!(contract.get_cookie_count(), 1);
+        }
+
+        #[ink::test]
+        fn update_cookie_works() {
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            assert_eq!(
+                contract.update_cookie(
+                    String::from("profile2"),
+                    String::from("cookie1"),
+                    b"2025-12-31".to_vec(),
+                    b"name2".to_vec(),
+                    b"secure2".to_vec(),
+                    b"/path2".to_vec(),
+                    b"value2".to_vec(),
+                ),
+                Ok(())
+            );
+        }
+
+        #[ink::test]
+        fn delete_cookie_works() {
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            let owner = contract.get_cookie(String::from("cookie1")).unwrap().owner;
+            assert_eq!(contract.delete_cookie(String::from("cookie1")), Ok(()));
+            assert_eq!(contract.get_cookie(String::from("cookie1")), None);
+            assert_eq!(contract.get_cookie_count(), 0);
+            assert!(contract.get_all_cookies(0, 10).items.is_empty());
+            assert!(contract.get_owned_cookies(owner, 0, 10).items.is_empty());
+            assert_eq!(
+                contract.delete_cookie(String::from("cookie1")),
+                Err(Error::CookieNotFound)
+            );
+        }
+
+        #[ink::test]
+        fn delete_cookie_requires_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.delete_cookie(String::from("cookie1")),
+                Err(Error::CookieNotFound)
+            );
+            assert_eq!(contract.get_cookie_count(), 1);
+        }
+
+        #[ink::test]
+        fn compartment_hierarchy_works() {
+            let mut contract = CookieContract::new();
+            let personal = contract.create_compartment(String::from("Personal")).unwrap();
+            let movies = contract.create_profile(personal, String::from("Movies")).unwrap();
+            let media = contract.create_profile(personal, String::from("Media")).unwrap();
+            let facebook = contract.create_domain(media, String::from("facebook.com")).unwrap();
+            contract.create_domain(movies, String::from("netflix.com")).unwrap();
+
+            assert_eq!(contract.get_compartments().len(), 1);
+            assert_eq!(contract.get_profiles(personal).unwrap().len(), 2);
+            assert_eq!(contract.get_domains(movies).unwrap()[0].name, "netflix.com");
+            assert_eq!(
+                contract.find_domain(String::from("facebook.com")).unwrap().profile_id,
+                media
+            );
+            assert_eq!(
+                contract.create_domain(movies, String::from("facebook.com")),
+                Err(Error::DomainAlreadyExists)
+            );
+
+            assert_eq!(contract.rename_domain(facebook, String::from("fb.com")), Ok(()));
+            assert_eq!(contract.find_domain(String::from("facebook.com")), None);
+            assert_eq!(contract.rename_profile(media, String::from("Social")), Ok(()));
+            assert_eq!(contract.get_profiles(personal).unwrap()[1].name, "Social");
+
+            assert_eq!(contract.remove_profile(media), Err(Error::ProfileNotEmpty));
+            assert_eq!(contract.remove_domain(facebook), Ok(()));
+            assert_eq!(contract.remove_profile(media), Ok(()));
+            assert_eq!(contract.remove_compartment(personal), Err(Error::CompartmentNotEmpty));
+        }
+
+        #[ink::test]
+        fn compartments_are_scoped_to_caller() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let personal = contract.create_compartment(String::from("Personal")).unwrap();
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert!(contract.get_compartments().is_empty());
+            assert_eq!(contract.get_profiles(personal), Err(Error::NotAuthorized));
+            assert_eq!(
+                contract.create_profile(personal, String::from("Movies")),
+                Err(Error::NotAuthorized)
+            );
+            assert_eq!(
+                contract.rename_compartment(personal, String::from("Mine")),
+                Err(Error::NotAuthorized)
+            );
+        }
+
+        #[ink::test]
+        fn cookie_history_works() {
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            for value in ["value2", "value3"] {
+                let _ = contract.update_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    b"2025-12-31".to_vec(),
+                    b"name1".to_vec(),
+                    b"secure1".to_vec(),
+                    b"/path1".to_vec(),
+                    value.as_bytes().to_vec(),
+                );
+            }
+
+            let history = contract.get_cookie_history(String::from("cookie1"), 0, 10);
+            assert_eq!(history.len(), 3);
+            assert_eq!(history[0].value, b"value1");
+            assert_eq!(history[2].value, b"value3");
+            let history = contract.get_cookie_history(String::from("cookie1"), 1, 1);
+            assert_eq!(history[0].value, b"value2");
+            assert_eq!(
+                contract.get_cookie_revision(String::from("cookie1"), 1).unwrap().expiration_date,
+                b"2025-12-31"
+            );
+
+            // Roll back to the registration snapshot
+            assert_eq!(contract.rollback_cookie(String::from("cookie1"), 0), Ok(()));
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.value, b"value1");
+            assert_eq!(entry.expiration_date, b"2024-12-31");
+            assert_eq!(entry.revision, 3);
+            assert_eq!(
+                contract.rollback_cookie(String::from("cookie1"), 9),
+                Err(Error::RevisionNotFound)
+            );
+        }
+
+        #[ink::test]
+        fn cookie_history_is_capped() {
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.set_max_revisions(0), Err(Error::InvalidLimit));
+            assert_eq!(contract.set_max_revisions(2), Ok(()));
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            for value in ["value2", "value3", "value4"] {
+                let _ = contract.update_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    b"2024-12-31".to_vec(),
+                    b"name1".to_vec(),
+                    b"secure1".to_vec(),
+                    b"/path1".to_vec(),
+                    value.as_bytes().to_vec(),
+                );
+            }
+
+            let history = contract.get_cookie_history(String::from("cookie1"), 0, 10);
+            assert_eq!(history.len(), 2);
+            assert_eq!(history[0].revision, 2);
+            assert_eq!(contract.get_cookie_revision(String::from("cookie1"), 1), None);
+
+            let _ = contract.delete_cookie(String::from("cookie1"));
+            assert_eq!(contract.get_cookie_revision(String::from("cookie1"), 3), None);
+        }
+
+        #[ink::test]
+        fn patch_cookie_works() {
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value1".to_vec(),
+            );
+            let patch = CookiePatch {
+                value: Some(b"value2".to_vec()),
+                path: Some(b"/path1".to_vec()),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie1"), patch), Ok(()));
+
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.value, b"value2");
+            assert_eq!(entry.name, b"name1");
+            assert_eq!(entry.revision, 1);
+
+            // Only the value actually changed
+            let events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            let decoded = <Event as scale::Decode>::decode(&mut &events[1].data[..]).unwrap();
+            match decoded {
+                Event::CookieUpdated(event) => assert_eq!(event.changed_fields, FIELD_VALUE),
+                _ => panic!("expected CookieUpdated"),
+            }
+
+            assert_eq!(
+                contract.patch_cookie(String::from("cookie2"), CookiePatch::default()),
+                Err(Error::CookieNotFound)
+            );
+        }
+
+        fn cookie_input(cookie: &str, value: &str) -> CookieInput {
+            CookieInput {
+                profile: String::from("profile1"),
+                cookie: String::from(cookie),
+                expiration_date: b"2024-12-31".to_vec(),
+                name: b"name1".to_vec(),
+                secure: b"secure1".to_vec(),
+                path: b"/path1".to_vec(),
+                value: value.as_bytes().to_vec(),
+                domain: plain("example.com"),
+                metadata: None,
+                envelope: None,
+                expires_at: None,
+            }
+        }
+
+        fn plain(domain: &str) -> DomainRef {
+            DomainRef::Plain(String::from(domain))
+        }
+
+        // Authorize an operator for the current caller and accept as the operator
+        fn add_operator(
+            contract: &mut CookieContract,
+            operator: AccountId,
+            profiles: Vec<String>,
+            expires_at: Option<BlockNumber>,
+        ) -> Result<(), Error> {
+            let account = ink::env::caller::<ink::env::DefaultEnvironment>();
+            contract.authorize_operator(operator, profiles, Vec::new(), expires_at)?;
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(operator);
+            let accepted = contract.accept_operator(account);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(account);
+            accepted
+        }
+
+        fn typed_metadata() -> CookieMetadata {
+            CookieMetadata {
+                secure: false,
+                http_only: true,
+                same_site: SameSite::Lax,
+                expires_at: Some(1_735_603_200),
+                host_only: true,
+                partitioned: false,
+            }
+        }
+
+        fn envelope(key_id: u32, ciphertext: Vec<u8>) -> Envelope {
+            Envelope {
+                version: ENVELOPE_VERSION,
+                algorithm: Algorithm::XChaCha20Poly1305,
+                key_id,
+                ephemeral_key: [9; 32],
+                nonce: vec![7; 24],
+                ciphertext,
+            }
+        }
+
+        #[ink::test]
+        fn batch_writes_work() {
+            let mut contract = CookieContract::new();
+            let results = contract
+                .register_cookies(vec![
+                    cookie_input("cookie1", "value1"),
+                    cookie_input("cookie2", "value1"),
+                ])
+                .unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[1].outcome, WriteOutcome::Created);
+            assert_eq!(contract.get_cookie_count(), 2);
+
+            let results = contract
+                .upsert_cookies(vec![
+                    cookie_input("cookie2", "value2"),
+                    cookie_input("cookie3", "value1"),
+                ])
+                .unwrap();
+            assert_eq!(results[0].outcome, WriteOutcome::Updated);
+            assert_eq!(results[1].outcome, WriteOutcome::Created);
+            assert_eq!(results[1].transaction_id, 4);
+            assert_eq!(contract.get_cookie(String::from("cookie2")).unwrap().value, b"value2");
+            assert_eq!(contract.get_cookie_count(), 3);
+
+            // A single aggregated event per batch
+            assert_eq!(ink::env::test::recorded_events().count(), 2);
+        }
+
+        #[ink::test]
+        fn batch_writes_are_all_or_nothing() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1")]);
+
+            assert_eq!(
+                contract.register_cookies(vec![
+                    cookie_input("cookie2", "value1"),
+                    cookie_input("cookie1", "value2"),
+                ]),
+                Err(Error::BatchItemFailed(1, Box::new(Error::CookieAlreadyExists)))
+            );
+            assert_eq!(
+                contract.upsert_cookies(vec![
+                    cookie_input("cookie2", "value1"),
+                    cookie_input("cookie2", "value2"),
+                ]),
+                Err(Error::BatchItemFailed(1, Box::new(Error::DuplicateCookie)))
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.set_max_batch_size(1), Err(Error::MissingRole(Role::Admin)));
+            assert_eq!(contract.get_cookie(String::from("cookie2")), None);
+            assert_eq!(contract.get_cookie_count(), 1);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.set_max_batch_size(1), Ok(()));
+            assert_eq!(
+                contract.register_cookies(vec![
+                    cookie_input("cookie2", "value1"),
+                    cookie_input("cookie3", "value1"),
+                ]),
+                Err(Error::BatchTooLarge)
+            );
+        }
+
+        #[ink::test]
+        fn upsert_cookie_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let writes = [("value1", WriteOutcome::Created), ("value2", WriteOutcome::Updated)];
+            for (value, outcome) in writes {
+                assert_eq!(
+                    contract.upsert_cookie(
+                        String::from("profile1"),
+                        String::from("cookie1"),
+                        plain("example.com"),
+                        b"2024-12-31".to_vec(),
+                        b"name1".to_vec(),
+                        b"secure1".to_vec(),
+                        b"/path1".to_vec(),
+                        value.as_bytes().to_vec(),
+                    ),
+                    Ok(outcome)
+                );
+            }
+            assert_eq!(contract.get_cookie(String::from("cookie1")).unwrap().value, b"value2");
+            assert_eq!(contract.get_cookie_count(), 1);
+
+            // Updating moves the cookie to the domain passed and keeps the expiry the legacy
+            // message does not carry
+            let mut input = cookie_input("cookie2", "value1");
+            input.expires_at = Some(2 * EXPIRY_BUCKET_SECS);
+            let _ = contract.register_cookies(vec![input]);
+            let upsert = contract.upsert_cookie(
+                String::from("profile1"),
+                String::from("cookie2"),
+                plain("shop.example.com"),
+                b"2024-12-31".to_vec(),
+                b"name1".to_vec(),
+                b"secure1".to_vec(),
+                b"/path1".to_vec(),
+                b"value2".to_vec(),
+            );
+            assert_eq!(upsert, Ok(WriteOutcome::Updated));
+            let entry = contract.get_cookie(String::from("cookie2")).unwrap();
+            assert_eq!(entry.domain, plain("shop.example.com"));
+            assert_eq!(entry.expires_at, Some(2 * EXPIRY_BUCKET_SECS));
+            let domain = String::from("shop.example.com");
+            let listed = contract.get_domain_cookies(accounts.alice, domain, 0, 10).items;
+            assert_eq!(listed, [String::from("cookie2")]);
+            let expiring = contract.get_expiring_between(0, 3 * EXPIRY_BUCKET_SECS, None, 10);
+            assert_eq!(expiring.unwrap().items, [(accounts.alice, String::from("cookie2"))]);
+
+            // Bob's upsert lands in his own namespace
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.upsert_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    plain("example.com"),
+                    b"2024-12-31".to_vec(),
+                    b"name1".to_vec(),
+                    b"secure1".to_vec(),
+                    b"/path1".to_vec(),
+                    b"value3".to_vec(),
+                ),
+                Ok(WriteOutcome::Created)
+            );
+            let alice_cookie = contract.get_cookie_of(accounts.alice, String::from("cookie1"));
+            assert_eq!(alice_cookie.unwrap().value, b"value2");
+        }
+
+        #[ink::test]
+        fn cookie_names_are_namespaced_per_owner() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            for (caller, value) in [(accounts.alice, "alice"), (accounts.bob, "bob")] {
+                ink::env::test::set_caller::<ink::env::DefaultEnvironment>(caller);
+                assert_eq!(
+                    contract.register_cookie(
+                        String::from("profile1"),
+                        String::from("sessionid"),
+                        plain("example.com"),
+                        b"2024-12-31".to_vec(),
+                        b"name1".to_vec(),
+                        b"secure1".to_vec(),
+                        b"/path1".to_vec(),
+                        value.as_bytes().to_vec(),
+                    ),
+                    Ok(())
+                );
+            }
+            assert_eq!(contract.get_cookie_count(), 2);
+            assert_eq!(contract.get_cookie(String::from("sessionid")).unwrap().value, b"bob");
+            let owned = contract.get_owned_cookies(accounts.alice, 0, 10);
+            assert_eq!(owned.items, vec![String::from("sessionid")]);
+
+            // Bob deleting his copy leaves Alice's untouched
+            assert_eq!(contract.delete_cookie(String::from("sessionid")), Ok(()));
+            let alice_cookie = contract.get_cookie_of(accounts.alice, String::from("sessionid"));
+            assert_eq!(alice_cookie.unwrap().value, b"alice");
+            assert_eq!(
+                contract.get_all_cookies(0, 10).items,
+                vec![(accounts.alice, String::from("sessionid"))]
+            );
+        }
+
+        #[ink::test]
+        fn listings_are_paginated() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let mut inputs = Vec::new();
+            for i in 0..5 {
+                let mut input = cookie_input(&format!("cookie{i}"), "value1");
+                if i % 2 == 0 {
+                    input.profile = String::from("profile2");
+                    input.domain = plain("example.org");
+                }
+                inputs.push(input);
+            }
+            let _ = contract.register_cookies(inputs);
+
+            let first = contract.get_all_cookies(0, 2);
+            assert_eq!(first.items.len(), 2);
+            assert_eq!(first.next, Some(2));
+            let last = contract.get_all_cookies(4, 2);
+            assert_eq!(last.items, vec![(accounts.alice, String::from("cookie4"))]);
+            assert_eq!(last.next, None);
+            assert!(contract.get_all_cookies(9, 2).items.is_empty());
+
+            let owned = contract.get_owned_cookies(accounts.alice, 1, 3);
+            assert_eq!(owned.items[0], "cookie1");
+            assert_eq!(owned.next, Some(4));
+
+            let profile2 = String::from("profile2");
+            let profile = contract.get_profile_cookies(accounts.alice, profile2, 0, 10);
+            assert_eq!(profile.items, vec!["cookie0", "cookie2", "cookie4"]);
+            let example = String::from("example.com");
+            let domain = contract.get_domain_cookies(accounts.alice, example, 0, 10);
+            assert_eq!(domain.items, vec!["cookie1", "cookie3"]);
+
+            // Patching the profile moves the cookie between profile listings
+            let patch = CookiePatch {
+                profile: Some(String::from("profile1")),
+                ..Default::default()
+            };
+            let _ = contract.patch_cookie(String::from("cookie0"), patch);
+            let profile1 = String::from("profile1");
+            let profile = contract.get_profile_cookies(accounts.alice, profile1, 0, 10);
+            assert_eq!(profile.items, vec!["cookie1", "cookie3", "cookie0"]);
+            let _ = contract.delete_cookie(String::from("cookie1"));
+            let example = String::from("example.com");
+            let domain = contract.get_domain_cookies(accounts.alice, example, 0, 10);
+            assert_eq!(domain.items, vec!["cookie3"]);
+        }
+
+        #[ink::test]
+        fn register_cost_is_constant() {
+            use ink::storage::traits::Storable;
+
+            let callee = ink::env::test::callee::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let root_size = |contract: &CookieContract| {
+                let mut encoded = Vec::new();
+                contract.encode(&mut encoded);
+                encoded.len()
+            };
+            let storage_rw = || {
+                ink::env::test::get_contract_storage_rw::<ink::env::DefaultEnvironment>(&callee)
+            };
+            let measure = |contract: &mut CookieContract, i: u32| {
+                let before = storage_rw();
+                let _ = contract.register_cookies(vec![cookie_input(&format!("cookie{i}"), "v")]);
+                let after = storage_rw();
+                (after.0 - before.0, after.1 - before.1)
+            };
+
+            let small = measure(&mut contract, 0);
+            let root_before = root_size(&contract);
+            for i in 1..200 {
+                measure(&mut contract, i);
+            }
+            let large = measure(&mut contract, 200);
+
+            // Same number of storage reads/writes and the same root size with 200 more cookies
+            assert!(small.1 > 0);
+            assert_eq!(small, large);
+            assert_eq!(root_before, root_size(&contract));
+            assert_eq!(contract.get_cookie_count(), 201);
+        }
+
+        #[ink::test]
+        fn typed_cookie_works() {
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            assert_eq!(
+                contract.register_typed_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    plain("example.com"),
+                    typed_metadata(),
+                    envelope(1, vec![1, 2, 3]),
+                ),
+                Ok(())
+            );
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.metadata, Some(typed_metadata()));
+            assert!(!entry.metadata.unwrap().secure);
+            assert_eq!(entry.envelope, Some(envelope(1, vec![1, 2, 3])));
+
+            // Re-sealed under a rotated key; rolling back restores the old envelope
+            assert_eq!(contract.rotate_public_key(KeyKind::X25519, [2; 32]), Ok(2));
+            let patch = CookiePatch {
+                envelope: Some(envelope(2, vec![4, 5])),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie1"), patch), Ok(()));
+            assert_eq!(contract.rollback_cookie(String::from("cookie1"), 0), Ok(()));
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.envelope, Some(envelope(1, vec![1, 2, 3])));
+        }
+
+        #[ink::test]
+        fn typed_cookie_validation_works() {
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            assert_eq!(contract.set_public_key(KeyKind::Sr25519, [2; 32]), Ok(2));
+            let cases = [
+                (
+                    CookieMetadata { expires_at: Some(0), ..typed_metadata() },
+                    envelope(1, vec![1]),
+                    Error::InvalidExpiration,
+                ),
+                (
+                    CookieMetadata { same_site: SameSite::NoRestriction, ..typed_metadata() },
+                    envelope(1, vec![1]),
+                    Error::SameSiteNoneRequiresSecure,
+                ),
+                (
+                    CookieMetadata { partitioned: true, ..typed_metadata() },
+                    envelope(1, vec![1]),
+                    Error::PartitionedRequiresSecure,
+                ),
+                (typed_metadata(), envelope(1, Vec::new()), Error::EmptyPayload),
+                (
+                    typed_metadata(),
+                    envelope(1, vec![0; MAX_PAYLOAD_LEN as usize + 1]),
+                    Error::PayloadTooLarge,
+                ),
+                (
+                    typed_metadata(),
+                    Envelope { version: 1, ..envelope(1, vec![1]) },
+                    Error::UnsupportedEnvelopeVersion,
+                ),
+                (
+                    typed_metadata(),
+                    Envelope { algorithm: Algorithm::Aes256Gcm, ..envelope(1, vec![1]) },
+                    Error::InvalidNonce,
+                ),
+                (typed_metadata(), envelope(2, vec![1]), Error::InvalidKey),
+                (typed_metadata(), envelope(3, vec![1]), Error::KeyNotFound),
+            ];
+            for (metadata, envelope, error) in cases {
+                assert_eq!(
+                    contract.register_typed_cookie(
+                        String::from("profile1"),
+                        String::from("cookie1"),
+                        plain("example.com"),
+                        metadata,
+                        envelope,
+                    ),
+                    Err(error)
+                );
+            }
+
+            let mut input = cookie_input("cookie1", "value1");
+            input.metadata = Some(CookieMetadata { partitioned: true, ..typed_metadata() });
+            input.envelope = Some(envelope(1, vec![1]));
+            input.expires_at = typed_metadata().expires_at;
+            let failed = Error::BatchItemFailed(0, Box::new(Error::PartitionedRequiresSecure));
+            assert_eq!(contract.register_cookies(vec![input]), Err(failed));
+            assert_eq!(contract.get_cookie_count(), 0);
+        }
+
+        #[ink::test]
+        fn typed_expiry_follows_metadata() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            let expires_at = Some(EXPIRY_BUCKET_SECS);
+            let metadata = CookieMetadata { expires_at, ..typed_metadata() };
+            let mut input = cookie_input("cookie1", "value1");
+            input.metadata = Some(metadata.clone());
+            input.envelope = Some(envelope(1, vec![1]));
+            let registered = contract.register_cookies(vec![input.clone()]);
+            let failed = Error::BatchItemFailed(0, Box::new(Error::InvalidExpiration));
+            assert_eq!(registered, Err(failed));
+            input.expires_at = expires_at;
+            assert!(contract.register_cookies(vec![input]).is_ok());
+
+            // New metadata moves the cookie's expiry and its place in the expiry index
+            let expiring = |contract: &CookieContract, from, to| {
+                contract.get_expiring_between(from, to, None, 10).unwrap().items
+            };
+            let key = (accounts.alice, String::from("cookie1"));
+            let later = Some(3 * EXPIRY_BUCKET_SECS);
+            let patch = CookiePatch {
+                metadata: Some(CookieMetadata { expires_at: later, ..metadata.clone() }),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie1"), patch), Ok(()));
+            assert_eq!(contract.get_cookie(String::from("cookie1")).unwrap().expires_at, later);
+            assert!(expiring(&contract, 0, 2 * EXPIRY_BUCKET_SECS).is_empty());
+            assert_eq!(expiring(&contract, 0, 4 * EXPIRY_BUCKET_SECS), vec![key.clone()]);
+
+            // A new expiry is written into the metadata too, and the two may not disagree
+            let patch = CookiePatch {
+                expires_at: Some(None),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie1"), patch), Ok(()));
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!((entry.expires_at, entry.metadata.unwrap().expires_at), (None, None));
+            let patch = CookiePatch {
+                metadata: Some(metadata),
+                expires_at: Some(later),
+                ..Default::default()
+            };
+            assert_eq!(
+                contract.patch_cookie(String::from("cookie1"), patch),
+                Err(Error::InvalidExpiration)
+            );
+        }
+
+        #[ink::test]
+        fn legacy_fields_shrink_when_decoded() {
+            use base64::{engine::general_purpose::STANDARD, Engine};
+            use scale::Encode;
+
+            // CryptoJS output: "Salted__", an 8 byte salt and two AES blocks
+            let ciphertext = (0..48).collect::<Vec<u8>>();
+            let legacy = STANDARD.encode(&ciphertext);
+            assert_eq!(legacy.len(), 64);
+
+            // A legacy client's base64 String arrives byte-for-byte as the same Vec<u8>
+            assert_eq!(legacy.encode(), legacy.as_bytes().to_vec().encode());
+
+            let mut contract = CookieContract::new();
+            let field = || legacy.as_bytes().to_vec();
+            let _ = contract.register_cookie(
+                String::from("profile1"),
+                String::from("cookie1"),
+                plain("example.com"),
+                field(),
+                field(),
+                field(),
+                field(),
+                field(),
+            );
+            let before = contract.get_cookie(String::from("cookie1")).unwrap().encoded_size();
+
+            assert_eq!(contract.decode_legacy_fields(String::from("cookie1")), Ok(()));
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.value, ciphertext);
+            assert_eq!(entry.path, ciphertext);
+
+            // Each of the five fields drops 16 bytes of text and a byte of length prefix
+            let after = entry.encoded_size();
+            assert_eq!(before - after, 5 * 17);
+
+            // Already decoded fields are left alone
+            assert_eq!(
+                contract.decode_legacy_fields(String::from("cookie1")),
+                Err(Error::InvalidEncoding)
+            );
+            assert_eq!(contract.get_cookie(String::from("cookie1")).unwrap(), entry);
+        }
+
+        #[ink::test]
+        fn public_key_rotation_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            assert_eq!(
+                contract.set_public_key(KeyKind::X25519, [2; 32]),
+                Err(Error::KeyAlreadySet)
+            );
+            assert_eq!(
+                contract.rotate_public_key(KeyKind::Sr25519, [2; 32]),
+                Err(Error::KeyNotFound)
+            );
+
+            // The rotated key stays readable by id
+            assert_eq!(contract.rotate_public_key(KeyKind::X25519, [2; 32]), Ok(2));
+            let active = contract.get_public_key(accounts.alice, KeyKind::X25519).unwrap();
+            assert_eq!((active.key_id, active.key), (2, [2; 32]));
+            let old = contract.get_public_key_by_id(accounts.alice, 1).unwrap();
+            assert_eq!((old.key, old.revoked_at), ([1; 32], None));
+
+            let events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            let decoded = <Event as scale::Decode>::decode(&mut &events[1].data[..]).unwrap();
+            match decoded {
+                Event::PublicKeySet(event) => assert_eq!(event.previous_key_id, Some(1)),
+                _ => panic!("expected PublicKeySet"),
+            }
+
+            // Revoking the active key leaves no active key and blocks new envelopes
+            assert_eq!(contract.revoke_public_key(2), Ok(()));
+            assert_eq!(contract.revoke_public_key(2), Err(Error::KeyRevoked));
+            assert_eq!(contract.revoke_public_key(9), Err(Error::KeyNotFound));
+            assert_eq!(contract.get_public_key(accounts.alice, KeyKind::X25519), None);
+            assert!(contract.get_public_key_by_id(accounts.alice, 2).unwrap().revoked_at.is_some());
+            assert_eq!(
+                contract.register_typed_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    plain("example.com"),
+                    typed_metadata(),
+                    envelope(2, vec![1]),
+                ),
+                Err(Error::KeyRevoked)
+            );
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [3; 32]), Ok(3));
+        }
+
+        #[ink::test]
+        fn sharing_works() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let mut input = cookie_input("cookie2", "value1");
+            input.profile = String::from("profile2");
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1"), input]);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            let _ = contract.set_public_key(KeyKind::X25519, [1; 32]);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+
+            let cookie1 = GrantTarget::Cookie(String::from("cookie1"));
+            let profile2 = GrantTarget::Profile(String::from("profile2"));
+            let cookie3 = GrantTarget::Cookie(String::from("cookie3"));
+            let mut share = |target: &GrantTarget, grantee, expires_at| {
+                let wrapped_key = envelope(1, vec![1]);
+                contract.share(target.clone(), grantee, Permission::Read, wrapped_key, expires_at)
+            };
+            assert_eq!(share(&cookie1, accounts.alice, None), Err(Error::InvalidGrantee));
+            assert_eq!(share(&cookie3, accounts.bob, None), Err(Error::CookieNotFound));
+            assert_eq!(share(&cookie1, accounts.bob, Some(0)), Err(Error::InvalidExpiration));
+            assert_eq!(share(&cookie1, accounts.charlie, None), Err(Error::KeyNotFound));
+
+            // Read access to one cookie, write access to a whole profile
+            assert_eq!(share(&cookie1, accounts.bob, Some(10)), Ok(()));
+            let (write, wrapped_key) = (Permission::Write, envelope(1, vec![2]));
+            assert_eq!(
+                contract.share(profile2.clone(), accounts.bob, write, wrapped_key, None),
+                Ok(())
+            );
+            assert_eq!(contract.get_received_grants(accounts.bob, 0, 10).items.len(), 2);
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            let (entry, grant) =
+                contract.get_shared_cookie(accounts.alice, String::from("cookie1")).unwrap();
+            assert_eq!(entry.value, b"value1");
+            assert_eq!(grant.wrapped_key.ciphertext, vec![1]);
+            let patch = || CookiePatch {
+                value: Some(b"value2".to_vec()),
+                ..Default::default()
+            };
+            assert_eq!(
+                contract.patch_shared_cookie(accounts.alice, String::from("cookie1"), patch()),
+                Err(Error::NotAuthorized)
+            );
+            assert_eq!(
+                contract.patch_shared_cookie(accounts.alice, String::from("cookie2"), patch()),
+                Ok(())
+            );
+            let entry = contract.get_cookie_of(accounts.alice, String::from("cookie2")).unwrap();
+            assert_eq!(entry.value, b"value2");
+
+            // Write grants change the cookie but never move it to another profile or domain
+            let moves = [
+                CookiePatch { profile: Some(String::from("profile1")), ..patch() },
+                CookiePatch { domain: Some(plain("other.com")), ..patch() },
+            ];
+            for patch in moves {
+                assert_eq!(
+                    contract.patch_shared_cookie(accounts.alice, String::from("cookie2"), patch),
+                    Err(Error::NotAuthorized)
+                );
+            }
+            let cookie2 = String::from("cookie2");
+            let unmoved = CookiePatch { profile: Some(String::from("profile2")), ..patch() };
+            let patched = contract.patch_shared_cookie(accounts.alice, cookie2.clone(), unmoved);
+            assert_eq!(patched, Ok(()));
+            let entry = contract.get_cookie_of(accounts.alice, cookie2).unwrap();
+            assert_eq!(entry.profile, "profile2");
+            assert_eq!(entry.domain, plain("example.com"));
+
+            // Grants lapse at their expiry block and disappear on revoke or delete
+            for _ in 0..10 {
+                ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
+            }
+            assert_eq!(
+                contract.get_shared_cookie(accounts.alice, String::from("cookie1")),
+                Err(Error::NotAuthorized)
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.revoke_share(profile2.clone(), accounts.bob), Ok(()));
+            assert_eq!(contract.revoke_share(profile2, accounts.bob), Err(Error::GrantNotFound));
+            assert_eq!(contract.delete_cookie(String::from("cookie1")), Ok(()));
+            assert_eq!(contract.get_grant(accounts.alice, cookie1, accounts.bob), None);
+            assert!(contract.get_received_grants(accounts.bob, 0, 10).items.is_empty());
+        }
+
+        #[ink::test]
+        fn content_keys_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let profile = String::from("profile1");
+            assert_eq!(
+                contract.set_content_key(profile.clone(), envelope(1, vec![1])),
+                Err(Error::KeyNotFound)
+            );
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            assert_eq!(contract.set_content_key(profile.clone(), envelope(1, vec![1])), Ok(()));
+            let stored = contract.get_content_key(accounts.alice, profile.clone());
+            assert_eq!(stored, Some(envelope(1, vec![1])));
+
+            // Re-wrapped after a rotation
+            assert_eq!(contract.rotate_public_key(KeyKind::X25519, [2; 32]), Ok(2));
+            assert_eq!(contract.set_content_key(profile.clone(), envelope(2, vec![1])), Ok(()));
+            let stored = contract.get_content_key(accounts.alice, profile);
+            assert_eq!(stored.map(|envelope| envelope.key_id), Some(2));
+            assert_eq!(contract.get_content_key(accounts.bob, String::from("profile1")), None);
+
+            let scoped = vec![String::from("profile2")];
+            let _ = add_operator(&mut contract, accounts.django, scoped, None);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            let profile1 = String::from("profile1");
+            assert_eq!(
+                contract.set_content_key(profile1, envelope(2, vec![3])),
+                Err(Error::OutOfScope)
+            );
+            let profile2 = String::from("profile2");
+            assert_eq!(contract.set_content_key(profile2, envelope(2, vec![3])), Ok(()));
+        }
+
+        #[ink::test]
+        fn roles_and_ownership_work() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            assert!(contract.has_role(Role::Admin, accounts.alice));
+            assert_eq!(contract.grant_role(Role::Pauser, accounts.bob), Ok(()));
+            assert!(contract.has_role(Role::Pauser, accounts.bob));
+            assert_eq!(contract.grant_role(Role::Operator, accounts.bob), Err(Error::InvalidRole));
+            assert_eq!(contract.grant_role(Role::Owner, accounts.bob), Err(Error::InvalidRole));
+            assert!(contract.has_role(Role::Owner, accounts.alice));
+            assert_eq!(
+                contract.revoke_role(Role::Admin, accounts.alice),
+                Err(Error::NotAuthorized)
+            );
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.set_max_revisions(4), Err(Error::MissingRole(Role::Admin)));
+            assert_eq!(
+                contract.grant_role(Role::Admin, accounts.bob),
+                Err(Error::MissingRole(Role::Admin))
+            );
+            assert_eq!(
+                contract.transfer_ownership(accounts.bob),
+                Err(Error::MissingRole(Role::Owner))
+            );
+
+            // Ownership only moves once the new owner accepts
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.transfer_ownership(accounts.bob), Ok(()));
+            assert_eq!(contract.get_owner(), accounts.alice);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.charlie);
+            assert_eq!(contract.accept_ownership(), Err(Error::NotAuthorized));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.accept_ownership(), Ok(()));
+            assert_eq!(contract.get_owner(), accounts.bob);
+            assert!(contract.has_role(Role::Owner, accounts.bob));
+            assert!(!contract.has_role(Role::Owner, accounts.alice));
+            assert_eq!(contract.get_pending_owner(), None);
+            assert_eq!(contract.set_max_revisions(4), Ok(()));
+
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.set_max_revisions(8), Err(Error::MissingRole(Role::Admin)));
+
+            // A contract deployed on behalf of someone else belongs to them
+            let contract = CookieContract::new_for(accounts.eve);
+            assert_eq!(contract.get_owner(), accounts.eve);
+            assert!(contract.has_role(Role::Admin, accounts.eve));
+            assert!(!contract.has_role(Role::Admin, accounts.alice));
+            assert!(!contract.is_vault());
+        }
+
+        #[ink::test]
+        fn operators_write_for_their_account() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let mut authorize = |operator, expires_at| {
+                contract.authorize_operator(operator, Vec::new(), Vec::new(), expires_at)
+            };
+            assert_eq!(authorize(accounts.alice, None), Err(Error::InvalidGrantee));
+            assert_eq!(authorize(accounts.django, Some(0)), Err(Error::InvalidExpiration));
+            assert_eq!(authorize(accounts.django, None), Ok(()));
+            assert!(!contract.has_role(Role::Operator, accounts.django));
+            assert!(contract.get_pending_operator(accounts.alice, accounts.django).is_some());
+
+            // Nobody but the operator itself can accept
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.accept_operator(accounts.alice), Err(Error::NotAuthorized));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            assert_eq!(contract.accept_operator(accounts.alice), Ok(()));
+            assert!(contract.has_role(Role::Operator, accounts.django));
+            assert_eq!(contract.get_pending_operator(accounts.alice, accounts.django), None);
 
-    #[ink(event)]
-    pub struct CookieDeleted {
-        cookie: String,
-        owner: AccountId,
-        block: BlockNumber,
-        transaction_id: u64,
-    }
+            // The device key's writes land in alice's namespace
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1")]);
+            let entry = contract.get_cookie_of(accounts.alice, String::from("cookie1")).unwrap();
+            assert_eq!(entry.owner, accounts.alice);
+            assert!(contract.get_cookie(String::from("cookie1")).is_some());
 
-    #[ink(storage)]
-    pub struct CookieContract {
-        owner: AccountId,
-        cookie_count: u32,
-        cookies: Mapping<String, CookieEntry>,
-        cookie_list: Vec<String>,
-        owned_cookies: Mapping<AccountId, Vec<String>>,
-        transaction_id_counter: u64,  // Counter for transaction IDs
-        public_keys: Mapping<AccountId, String>,  // Store public keys of users
-        last_stages: Mapping<AccountId, String>,  // Last stage (profile, domain, etc.)
-    }
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.revoke_operator(accounts.django), Err(Error::NotAuthorized));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.revoke_operator(accounts.django), Ok(()));
 
-    impl CookieContract {
-        #[ink(constructor)]
-        pub fn new() -> Self {
-            Self {
-                owner: Self::env().caller(),
-                cookie_count: 0,
-                cookies: Mapping::default(),
-                cookie_list: Vec::new(),
-                owned_cookies: Mapping::default(),
-                transaction_id_counter: 0,
-                public_keys: Mapping::default(),
-                last_stages: Mapping::default(),
-            }
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            assert_eq!(contract.get_cookie(String::from("cookie1")), None);
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1")]);
+            assert!(contract.get_cookie_of(accounts.django, String::from("cookie1")).is_some());
         }
 
-        // Register a new cookie with transaction ID
-        #[ink(message)]
-        pub fn register_cookie(
-            &mut self,
-            profile: String,
-            cookie: String,
-            expiration_date: String,
-            name: String,
-            secure: String,
-            path: String,
-            value: String,
-        ) -> Result<(), Error> {
-            let caller = self.env().caller();
-            let current_block = self.env().block_number();
+        #[ink::test]
+        fn operators_are_scoped_and_expire() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let mut input = cookie_input("cookie2", "value1");
+            input.profile = String::from("profile2");
+            let _ = contract.register_cookies(vec![input.clone()]);
+            let profiles = vec![String::from("profile1")];
+            assert_eq!(add_operator(&mut contract, accounts.django, profiles, Some(5)), Ok(()));
+            assert_eq!(contract.get_operators(accounts.alice, 0, 10).items, vec![accounts.django]);
 
-            // Check if cookie already exists - duplicate
-            if self.cookies.contains(&cookie) {
-                return Err(Error::CookieAlreadyExists);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            assert!(contract.register_cookies(vec![cookie_input("cookie1", "value1")]).is_ok());
+            input.cookie = String::from("cookie3");
+            let failed = Error::BatchItemFailed(0, Box::new(Error::OutOfScope));
+            assert_eq!(contract.register_cookies(vec![input]), Err(failed));
+
+            // Cookies of other profiles can be neither read, changed nor moved into scope
+            let patch = CookiePatch {
+                profile: Some(String::from("profile2")),
+                ..Default::default()
+            };
+            assert_eq!(
+                contract.patch_cookie(String::from("cookie1"), patch),
+                Err(Error::OutOfScope)
+            );
+            assert_eq!(contract.get_cookie(String::from("cookie2")), None);
+            assert_eq!(contract.delete_cookie(String::from("cookie2")), Err(Error::OutOfScope));
+            assert_eq!(
+                contract.patch_cookie(String::from("cookie2"), CookiePatch::default()),
+                Err(Error::OutOfScope)
+            );
+
+            // An expired device key can no longer act for the account
+            for _ in 0..5 {
+                ink::env::test::advance_block::<ink::env::DefaultEnvironment>();
             }
+            assert!(!contract.has_role(Role::Operator, accounts.django));
+            assert_eq!(
+                contract.delete_cookie(String::from("cookie1")),
+                Err(Error::MissingRole(Role::Operator))
+            );
+        }
 
-            // Generate transaction ID for the current operation
-            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
-            let transaction_id = self.transaction_id_counter;
+        #[ink::test]
+        fn operators_must_accept() {
+            type Env = ink::env::DefaultEnvironment;
+            let accounts = ink::env::test::default_accounts::<Env>();
+            let mut contract = CookieContract::new();
+            ink::env::test::set_caller::<Env>(accounts.bob);
+            let _ = contract.register_cookies(vec![cookie_input("bobs", "value1")]);
 
-            let cookie_entry = CookieEntry {
-                profile,
-                cookie: cookie.clone(),
-                expiration_date,
-                name,
-                secure,
-                path,
-                value,
-                created_at: current_block,
-                owner: caller,
-                transaction_id,
+            // Eve cannot pull bob's calls into her namespace without his consent
+            ink::env::test::set_caller::<Env>(accounts.eve);
+            let authorize = |contract: &mut CookieContract, operator| {
+                contract.authorize_operator(operator, Vec::new(), Vec::new(), None)
             };
+            assert_eq!(authorize(&mut contract, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<Env>(accounts.bob);
+            assert!(!contract.has_role(Role::Operator, accounts.bob));
+            assert!(contract.get_cookie(String::from("bobs")).is_some());
+            let _ = contract.register_cookies(vec![cookie_input("cookie2", "value1")]);
+            assert!(contract.get_cookie_of(accounts.bob, String::from("cookie2")).is_some());
+            assert_eq!(contract.get_cookie_of(accounts.eve, String::from("cookie2")), None);
+            assert_eq!(authorize(&mut contract, accounts.charlie), Ok(()));
 
-            // Update storage
-            self.cookies.insert(&cookie, &cookie_entry);
-            self.cookie_list.push(cookie.clone());
-            self.cookie_count = self.cookie_count.wrapping_add(1);
+            // A withdrawn authorization can no longer be accepted
+            ink::env::test::set_caller::<Env>(accounts.eve);
+            assert_eq!(contract.revoke_operator(accounts.bob), Ok(()));
+            ink::env::test::set_caller::<Env>(accounts.bob);
+            assert_eq!(contract.accept_operator(accounts.eve), Err(Error::NotAuthorized));
 
-            // Update owned cookies
-            let mut owned = self.owned_cookies.get(caller).unwrap_or_default();
-            owned.push(cookie.clone());
-            self.owned_cookies.insert(caller, &owned);
+            // An operator that accepted can resign on its own
+            ink::env::test::set_caller::<Env>(accounts.eve);
+            assert_eq!(authorize(&mut contract, accounts.django), Ok(()));
+            ink::env::test::set_caller::<Env>(accounts.django);
+            assert_eq!(contract.accept_operator(accounts.eve), Ok(()));
+            assert_eq!(contract.accept_operator(accounts.bob), Err(Error::NotAuthorized));
+            assert_eq!(contract.resign_operator(), Ok(()));
+            assert!(!contract.has_role(Role::Operator, accounts.django));
+            assert!(contract.get_operators(accounts.eve, 0, 10).items.is_empty());
+            assert_eq!(contract.resign_operator(), Err(Error::MissingRole(Role::Operator)));
+        }
 
-            // Emit event
-            self.env().emit_event(CookieRegistered {
-                cookie,
-                owner: caller,
-                block: current_block,
-                transaction_id,
-            });
+        #[ink::test]
+        fn pause_blocks_writes() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1")]);
+            let _ = add_operator(&mut contract, accounts.django, Vec::new(), None);
 
-            Ok(())
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(
+                contract.pause(String::from("bad secure flags")),
+                Err(Error::MissingRole(Role::Pauser))
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.grant_role(Role::Pauser, accounts.bob), Ok(()));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.pause(String::from("bad secure flags")), Ok(()));
+            assert_eq!(contract.pause(String::from("again")), Err(Error::Paused));
+
+            // Writes fail, reads and revocations keep working
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert!(contract.is_paused());
+            assert_eq!(
+                contract.register_cookies(vec![cookie_input("cookie2", "value1")]),
+                Err(Error::Paused)
+            );
+            assert_eq!(contract.delete_cookie(String::from("cookie1")), Err(Error::Paused));
+            assert_eq!(contract.create_compartment(String::from("Personal")), Err(Error::Paused));
+            assert!(contract.get_cookie(String::from("cookie1")).is_some());
+            assert_eq!(contract.revoke_operator(accounts.django), Ok(()));
+
+            let events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            let decoded = <Event as scale::Decode>::decode(&mut &events[4].data[..]).unwrap();
+            match decoded {
+                Event::Paused(event) => assert_eq!(event.reason, "bad secure flags"),
+                _ => panic!("expected Paused"),
+            }
+
+            assert_eq!(contract.unpause(String::from("fixed in 1.2.1")), Ok(()));
+            assert_eq!(contract.unpause(String::from("again")), Err(Error::NotPaused));
+            assert!(contract.delete_cookie(String::from("cookie1")).is_ok());
         }
 
-        // Incremental update of an existing cookie
-        #[ink(message)]
-        pub fn update_cookie(
-            &mut self,
-            profile: String,
-            cookie: String,
-            expiration_date: String,
-            name: String,
-            secure: String,
-            path: String,
-            value: String,
-        ) -> Result<(), Error> {
-            let caller = self.env().caller();
-            let current_block = self.env().block_number();
+        #[ink::test]
+        fn migration_decodes_legacy_storage() {
+            use base64::{engine::general_purpose::STANDARD, Engine};
+            use scale::Encode;
 
-            // Check if cookie exists and caller is owner
-            let mut entry = self.cookies.get(&cookie).ok_or(Error::CookieNotFound)?;
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let mut contract = CookieContract::new();
+            assert_eq!(contract.get_storage_version(), STORAGE_VERSION);
+            assert_eq!(contract.migrate(10), Ok(None));
 
-            if entry.owner != caller {
-                return Err(Error::NotAuthorized);
+            // Cookies written by a client of the pre-versioning contract
+            let inputs = ["cookie1", "cookie2", "cookie3"].map(|cookie| {
+                let mut input = cookie_input(cookie, "");
+                input.value = STANDARD.encode(format!("{cookie} value")).into_bytes();
+                input
+            });
+            let _ = contract.register_cookies(inputs.to_vec());
+            contract.storage_version.set(&0);
+
+            // Entries of storage version 0 and 1 end before `expires_at` and `deposit`, and
+            // store the domain as a bare string
+            for key in contract.cookie_list.to_vec(&()) {
+                let mut legacy = contract.cookies.get(&key).unwrap().encode();
+                legacy.truncate(legacy.len() - (None::<u64>, Balance::default()).encoded_size());
+                legacy.remove((String::from("profile1"), &key.1).encoded_size());
+                ink::env::set_contract_storage(&(contract.cookies.key(), &key), &RawValue(legacy));
+                let mut legacy = contract.cookie_revisions.get((&key, 0)).unwrap().encode();
+                legacy.truncate(legacy.len() - None::<u64>.encoded_size());
+                let root = contract.cookie_revisions.key();
+                ink::env::set_contract_storage(&(root, (&key, 0)), &RawValue(legacy));
             }
 
-            // Increment transaction ID for each update
-            self.transaction_id_counter = self.transaction_id_counter.wrapping_add(1);
-            let transaction_id = self.transaction_id_counter;
+            assert_eq!(
+                contract.register_cookies(vec![cookie_input("cookie4", "value1")]),
+                Err(Error::MigrationPending)
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.migrate(2), Err(Error::MissingRole(Role::Owner)));
+            let upgrade = contract.upgrade(Hash::from([1; 32]));
+            assert_eq!(upgrade, Err(Error::MissingRole(Role::Owner)));
+
+            // Bounded batches resume where the previous call stopped
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.alice);
+            assert_eq!(contract.migrate(0), Err(Error::InvalidLimit));
+            assert_eq!(contract.migrate(2), Ok(Some(2)));
+            assert_eq!(contract.get_storage_version(), 0);
+            assert_eq!(contract.migrate(2), Ok(None));
+            assert_eq!(contract.get_storage_version(), STORAGE_VERSION);
 
-            // Create new entry with updated values and new transaction ID
-            let cookie_entry = CookieEntry {
+            let entry = contract.get_cookie(String::from("cookie3")).unwrap();
+            assert_eq!(entry.value, b"cookie3 value");
+            let entry = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(entry.value, b"cookie1 value");
+            assert_eq!((entry.expires_at, entry.deposit), (None, 0));
+            assert_eq!(entry.domain, plain("example.com"));
+            let revision = contract.get_cookie_revision(String::from("cookie1"), 0).unwrap();
+            assert_eq!(revision.value, b"cookie1 value");
+            assert!(contract.register_cookies(vec![cookie_input("cookie4", "value1")]).is_ok());
+        }
+
+        #[ink::test]
+        fn migration_upgrades_envelopes() {
+            use scale::Encode;
+
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let owner = accounts.alice;
+            let mut contract = CookieContract::new();
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [2; 32]), Ok(1));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(owner);
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            let (profile, cookie) = (String::from("profile1"), String::from("cookie1"));
+            let register = contract.register_typed_cookie(
                 profile,
-                cookie: cookie.clone(),
-                expiration_date,
-                name,
-                secure,
-                path,
-                value,
-                created_at: entry.created_at,  // Retain original creation time
-                owner: caller,
-                transaction_id,
+                cookie.clone(),
+                plain("example.com"),
+                typed_metadata(),
+                envelope(1, vec![1, 2, 3]),
+            );
+            assert_eq!(register, Ok(()));
+            let patch = CookiePatch {
+                envelope: Some(envelope(1, vec![4, 5])),
+                ..Default::default()
             };
+            assert_eq!(contract.patch_cookie(cookie.clone(), patch), Ok(()));
+            let read = Permission::Read;
+            let targets = [
+                GrantTarget::Cookie(cookie.clone()),
+                GrantTarget::Profile(String::from("profile2")),  // No cookies to reach it by
+            ];
+            for target in targets.clone() {
+                let wrapped_key = envelope(1, vec![6]);
+                assert_eq!(contract.share(target, accounts.bob, read, wrapped_key, None), Ok(()));
+            }
+            contract.storage_version.set(&5);
 
-            // Update storage
-            self.cookies.insert(&cookie, &cookie_entry);
-
-            // Emit event
-            self.env().emit_event(CookieUpdated {
-                cookie,
-                owner: caller,
-                block: current_block,
-                transaction_id,
+            // Storage version 5 wrote version 1 envelopes without an ephemeral key
+            let legacy = |mut bytes: Vec<u8>| {
+                let at = bytes.windows(32).position(|window| window == [9; 32]).unwrap();
+                bytes.drain(at..at + 32);
+                bytes[at - 6] = 1;
+                RawValue(bytes)
+            };
+            let migrated = |ciphertext| Envelope {
+                version: 1,
+                ephemeral_key: [0; 32],
+                ..envelope(1, ciphertext)
+            };
+            let key = (owner, cookie.clone());
+            let entry = legacy(contract.cookies.get(&key).unwrap().encode());
+            ink::env::set_contract_storage(&(contract.cookies.key(), &key), &entry);
+            let root = contract.cookie_revisions.key();
+            let revisions = [0, 1].map(|revision| {
+                legacy(contract.cookie_revisions.get((&key, revision)).unwrap().encode())
             });
+            for target in targets.clone() {
+                let grant_key = ((owner, target), accounts.bob);
+                let grant = legacy(contract.grants.get(&grant_key).unwrap().encode());
+                ink::env::set_contract_storage(&(contract.grants.key(), &grant_key), &grant);
+            }
 
-            Ok(())
-        }
+            // A value that decodes in neither layout stops the migration instead of trapping
+            // later reads
+            ink::env::set_contract_storage(&(root, (&key, 1)), &RawValue(vec![1, 2]));
+            assert_eq!(contract.migrate(10), Err(Error::MigrationFailed(key.clone())));
+            for (revision, bytes) in revisions.iter().enumerate() {
+                ink::env::set_contract_storage(&(root, (&key, revision as u32)), bytes);
+            }
+            assert_eq!(contract.migrate(10), Ok(None));
+            assert_eq!(contract.get_storage_version(), STORAGE_VERSION);
 
-        // Set or update public key for a user
-        #[ink(message)]
-        pub fn set_public_key(&mut self, public_key: String) -> Result<(), Error> {
-            let caller = self.env().caller();
-            self.public_keys.insert(caller, &public_key);
-            Ok(())
+            let entry = contract.get_cookie(cookie.clone()).unwrap();
+            assert_eq!(entry.envelope, Some(migrated(vec![4, 5])));
+            assert_eq!(entry.metadata, Some(typed_metadata()));
+            let revision = contract.get_cookie_revision(cookie.clone(), 0).unwrap();
+            assert_eq!(revision.envelope, Some(migrated(vec![1, 2, 3])));
+            for target in targets {
+                let grant = contract.get_grant(owner, target, accounts.bob).unwrap();
+                assert_eq!(grant.wrapped_key, migrated(vec![6]));
+            }
+            let patch = CookiePatch { envelope: Some(envelope(1, vec![7])), ..Default::default() };
+            assert_eq!(contract.patch_cookie(cookie, patch), Ok(()));
         }
 
-        // Get a user's public key
-        #[ink(message)]
-        pub fn get_public_key(&self, account: AccountId) -> Option<String> {
-            self.public_keys.get(account)
-        }
+        #[ink::test]
+        fn vault_listings_are_private() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
+            let owner = accounts.alice;
+            let mut contract = CookieContract::new_vault(owner);
+            assert!(contract.is_vault());
+            let _ = contract.register_cookies(vec![cookie_input("cookie1", "value1")]);
+            let _ = add_operator(&mut contract, accounts.django, Vec::new(), None);
+            assert_eq!(contract.get_all_cookies(0, 10).items.len(), 1);
 
-        // Set or update the last stage for the user (profile, domain, etc.)
-        #[ink(message)]
-        pub fn set_last_stage(&mut self, stage: String) -> Result<(), Error> {
-            let caller = self.env().caller();
-            self.last_stages.insert(caller, &stage);
-            Ok(())
-        }
+            // Anyone may register a key to be shared with, only the owner's side writes
+            let cookie1 = GrantTarget::Cookie(String::from("cookie1"));
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.set_public_key(KeyKind::X25519, [1; 32]), Ok(1));
+            assert_eq!(
+                contract.register_cookies(vec![cookie_input("cookie2", "value1")]),
+                Err(Error::NotAuthorized)
+            );
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            assert!(contract.register_cookies(vec![cookie_input("cookie2", "value1")]).is_ok());
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(owner);
+            let wrapped_key = envelope(1, vec![1]);
+            let read = Permission::Read;
+            assert_eq!(
+                contract.share(cookie1.clone(), accounts.bob, read, wrapped_key, None),
+                Ok(())
+            );
 
-        // Get the last stage associated with the user
-        #[ink(message)]
-        pub fn get_last_stage(&self) -> Option<String> {
-            let caller = self.env().caller();
-            self.last_stages.get(caller)
-        }
+            // Operators of the owner may list, anyone else sees nothing but grants to itself
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            assert_eq!(contract.get_owned_cookies(owner, 0, 10).items.len(), 2);
+            assert_eq!(contract.get_target_grants(owner, cookie1.clone(), 0, 10).items.len(), 1);
+            assert_eq!(contract.get_operators(owner, 0, 10).items, [accounts.django]);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.bob);
+            assert_eq!(contract.get_received_grants(accounts.bob, 0, 10).items.len(), 1);
+            assert!(contract.get_target_grants(owner, cookie1, 0, 10).items.is_empty());
+            assert!(contract.get_operators(owner, 0, 10).items.is_empty());
+            assert!(contract.get_received_grants(accounts.eve, 0, 10).items.is_empty());
+            assert!(contract.get_all_cookies(0, 10).items.is_empty());
+            assert!(contract.get_owned_cookies(owner, 0, 10).items.is_empty());
+            let profile = String::from("profile1");
+            assert!(contract.get_profile_cookies(owner, profile, 0, 10).items.is_empty());
+            let domain = String::from("example.com");
+            assert!(contract.get_domain_cookies(owner, domain, 0, 10).items.is_empty());
+            assert_eq!(contract.get_cookie_of(owner, String::from("cookie1")), None);
+            assert_eq!(contract.get_transaction(1), None);
+            assert!(contract.get_transactions_since(0, 10).is_empty());
+            let upgrade = contract.upgrade(Hash::from([1; 32]));
+            assert_eq!(upgrade, Err(Error::NotAuthorized));
 
-        // Get cookie details
-        #[ink(message)]
-        pub fn get_cookie(&self, cookie: String) -> Option<CookieEntry> {
-            self.cookies.get(&cookie)
+            // Not even its owner moves a vault to new code past the registry
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(accounts.django);
+            let mut vault = CookieContract::new_vault(owner);
+            ink::env::test::set_caller::<ink::env::DefaultEnvironment>(owner);
+            assert_eq!(vault.upgrade(Hash::from([1; 32])), Err(Error::NotAuthorized));
         }
 
-        // Get all cookies
-        #[ink(message)]
-        pub fn get_all_cookies(&self) -> Vec<String> {
-            self.cookie_list.clone()
-        }
+        #[ink::test]
+        fn expired_cookies_are_pruned() {
+            type Env = ink::env::DefaultEnvironment;
+            let accounts = ink::env::test::default_accounts::<Env>();
+            let mut contract = CookieContract::new();
+            ink::env::test::set_account_balance::<Env>(ink::env::test::callee::<Env>(), 1_000);
 
-        // Get cookies owned by an account
-        #[ink(message)]
-        pub fn get_owned_cookies(&self, account: AccountId) -> Vec<String> {
-            self.owned_cookies.get(account).unwrap_or_default()
-        }
+            // Two new cookies split the deposit, the remainder going to the first
+            let mut expiring = cookie_input("cookie1", "value1");
+            expiring.expires_at = Some(10);
+            let inputs = vec![expiring, cookie_input("cookie2", "value1")];
+            ink::env::test::set_value_transferred::<Env>(31);
+            assert!(contract.register_cookies(inputs).is_ok());
+            let upsert = contract.upsert_cookies(vec![cookie_input("cookie2", "value2")]);
+            assert_eq!(upsert, Err(Error::InvalidDeposit));
+            ink::env::test::set_value_transferred::<Env>(0);
+            assert_eq!(contract.get_cookie(String::from("cookie1")).unwrap().deposit, 16);
+            assert_eq!(contract.get_cookie(String::from("cookie2")).unwrap().deposit, 15);
+            assert!(contract.get_unexpired_cookie(String::from("cookie1")).is_some());
 
-        // Get total number of cookies
-        #[ink(message)]
-        pub fn get_cookie_count(&self) -> u32 {
-            self.cookie_count
+            // Expired cookies are hidden on request and anyone may prune them
+            ink::env::test::set_block_timestamp::<Env>(10_000);
+            assert_eq!(contract.get_unexpired_cookie(String::from("cookie1")), None);
+            assert!(contract.get_cookie(String::from("cookie1")).is_some());
+            let balance = ink::env::test::get_account_balance::<Env>(accounts.alice).unwrap();
+            ink::env::test::set_caller::<Env>(accounts.bob);
+            assert_eq!(contract.prune_expired(0), Err(Error::InvalidLimit));
+            assert_eq!(contract.prune_expired(10), Ok(1));
+            assert_eq!(contract.prune_expired(10), Ok(0));
+            let remaining = contract.get_all_cookies(0, 10).items;
+            assert_eq!(remaining, vec![(accounts.alice, String::from("cookie2"))]);
+            assert_eq!(
+                ink::env::test::get_account_balance::<Env>(accounts.alice),
+                Ok(balance + 16)
+            );
+
+            let events = ink::env::test::recorded_events().collect::<Vec<_>>();
+            let decoded = <Event as scale::Decode>::decode(&mut &events[1].data[..]).unwrap();
+            match decoded {
+                Event::CookieDeleted(event) => assert_eq!(event.cookie, "cookie1"),
+                _ => panic!("expected CookieDeleted"),
+            }
         }
 
-        // Get contract owner
-        #[ink(message)]
-        pub fn get_owner(&self) -> AccountId {
-            self.owner
+        #[ink::test]
+        fn failed_refunds_do_not_block_pruning() {
+            type Env = ink::env::DefaultEnvironment;
+            let accounts = ink::env::test::default_accounts::<Env>();
+            // A contract account without balance cannot pay anything out
+            let callee = AccountId::from([0x42; 32]);
+            ink::env::test::set_callee::<Env>(callee);
+            let mut contract = CookieContract::new();
+            let mut expiring = cookie_input("cookie1", "value1");
+            expiring.expires_at = Some(10);
+            ink::env::test::set_value_transferred::<Env>(1);
+            assert!(contract.register_cookies(vec![expiring]).is_ok());
+            ink::env::test::set_value_transferred::<Env>(0);
+
+            ink::env::test::set_block_timestamp::<Env>(10_000);
+            ink::env::test::set_caller::<Env>(accounts.bob);
+            assert_eq!(contract.prune_expired(10), Ok(1));
+            assert_eq!(contract.get_cookie_count(), 0);
+            assert_eq!(contract.get_unclaimed_deposits(accounts.alice), 1);
+
+            // The owner collects the deposit once it can be paid
+            ink::env::test::set_account_balance::<Env>(callee, 100);
+            let balance = ink::env::test::get_account_balance::<Env>(accounts.alice).unwrap();
+            ink::env::test::set_caller::<Env>(accounts.alice);
+            assert_eq!(contract.claim_deposits(), Ok(1));
+            assert_eq!(contract.claim_deposits(), Ok(0));
+            assert_eq!(contract.get_unclaimed_deposits(accounts.alice), 0);
+            assert_eq!(ink::env::test::get_account_balance::<Env>(accounts.alice), Ok(balance + 1));
         }
-    }
 
-    //***************TEST CASES***************//
-    #[cfg(test)]
-    mod tests {
-        use super::*;
+        #[ink::test]
+        fn expiry_index_bounds_pruning() {
+            type Env = ink::env::DefaultEnvironment;
+            let accounts = ink::env::test::default_accounts::<Env>();
+            let mut contract = CookieContract::new();
+            let day = EXPIRY_BUCKET_SECS;
+            let expiring = |cookie: &str, expires_at| CookieInput {
+                expires_at: Some(expires_at),
+                ..cookie_input(cookie, "value1")
+            };
+            let inputs = vec![
+                expiring("cookie1", day + 5),
+                expiring("cookie2", day + 1),
+                expiring("cookie3", 3 * day),
+                expiring("cookie4", 400 * day),
+            ];
+            let _ = contract.register_cookies(inputs);
+            let inputs = (0..40).map(|index| cookie_input(&format!("other{index}"), "value1"));
+            let _ = contract.register_cookies(inputs.collect());
+
+            let key = |cookie: &str| (accounts.alice, String::from(cookie));
+            let expiring = |from, to, start, limit| {
+                contract.get_expiring_between(from, to, start, limit)
+            };
+            let page = expiring(day, 4 * day, None, 10).unwrap();
+            assert_eq!(page.items, [key("cookie1"), key("cookie2"), key("cookie3")]);
+            assert_eq!(page.next, None);
+            let page = expiring(day + 2, 3 * day, None, 10).unwrap();
+            assert_eq!(page.items, [key("cookie1")]);
+            assert_eq!(expiring(day, day, None, 10), Err(Error::InvalidRange));
+            assert_eq!(expiring(0, 400 * day, None, 10), Err(Error::InvalidRange));
+            assert_eq!(expiring(day, 2 * day, Some((2, 0)), 10), Err(Error::InvalidRange));
+
+            // Pages resume at the bucket and position the previous one stopped at
+            let page = expiring(day, 4 * day, None, 2).unwrap();
+            assert_eq!(page.items, [key("cookie1"), key("cookie2")]);
+            assert_eq!(page.next, Some((1, 2)));
+            let page = expiring(day, 4 * day, page.next, 2).unwrap();
+            assert_eq!((page.items, page.next), (vec![key("cookie3")], None));
+
+            // Moving an expiry moves the cookie between buckets
+            let patch = CookiePatch {
+                expires_at: Some(Some(2 * day)),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie4"), patch), Ok(()));
+            let expiring = contract.get_expiring_between(2 * day, 3 * day, None, 10);
+            assert_eq!(expiring.unwrap().items, [key("cookie4")]);
+
+            // Each call does at most `limit` steps, removals and empty buckets alike
+            ink::env::test::set_block_timestamp::<Env>(10 * day * 1000);
+            assert_eq!(contract.prune_expired(2), Ok(2));
+            let callee = ink::env::test::callee::<Env>();
+            let (reads, _) = ink::env::test::get_contract_storage_rw::<Env>(&callee);
+            assert_eq!(contract.prune_expired(1), Ok(0));
+            let (reads_after, _) = ink::env::test::get_contract_storage_rw::<Env>(&callee);
+            assert!(reads_after - reads <= 4, "{} reads", reads_after - reads);
+            assert_eq!(contract.prune_expired(3), Ok(2));
+            assert_eq!(contract.prune_expired(100), Ok(0));
+            assert_eq!(contract.get_cookie_count(), 40);
+            assert_eq!(contract.get_expiring_between(0, 365 * day, None, 10), Ok(Page::default()));
+
+            // Writes only take expiries still ahead of the block time
+            let input = CookieInput {
+                expires_at: Some(10 * day),
+                ..cookie_input("cookie5", "value1")
+            };
+            let failed = Error::BatchItemFailed(0, Box::new(Error::InvalidExpiration));
+            assert_eq!(contract.register_cookies(vec![input]), Err(failed));
+            let other0 = String::from("other0");
+            let patch = |expires_at| CookiePatch {
+                expires_at: Some(expires_at),
+                ..Default::default()
+            };
+            let patched = contract.patch_cookie(other0.clone(), patch(Some(day)));
+            assert_eq!(patched, Err(Error::InvalidExpiration));
+            assert_eq!(contract.patch_cookie(other0.clone(), patch(Some(11 * day))), Ok(()));
+            assert_eq!(contract.patch_cookie(other0.clone(), patch(None)), Ok(()));
+
+            // Neither may a rollback restore one that passed. A migrated expiry long past
+            // moves the prune cursor back no further than the grace window.
+            ink::env::test::set_block_timestamp::<Env>(100 * day * 1000);
+            let rollback = contract.rollback_cookie(other0.clone(), 1);
+            assert_eq!(rollback, Err(Error::InvalidExpiration));
+            assert_eq!(contract.prune_expired(100), Ok(0));
+            assert_eq!(contract.prune_bucket.get(), Some(100));
+            contract.reindex_expiry(&(accounts.alice, other0), None, Some(11 * day));
+            assert_eq!(contract.prune_bucket.get(), Some(100 - PRUNE_GRACE_BUCKETS));
+        }
 
         #[ink::test]
-        fn register_cookie_works() {
+        fn host_lookup_matches_parent_domains() {
             let mut contract = CookieContract::new();
+            let profile = String::from("profile1");
+            let inputs = [
+                ("parent", ".Example.COM"),
+                ("www", "www.example.com"),
+                ("other", "other.com"),
+            ]
+            .map(|(cookie, domain)| CookieInput {
+                domain: plain(domain),
+                ..cookie_input(cookie, "value1")
+            });
+            let _ = contract.register_cookies(inputs.to_vec());
+            let _ = contract.set_public_key(KeyKind::X25519, [1; 32]);
+            let envelope = envelope(1, vec![1; 16]);
+            let metadata = typed_metadata();
+            let domain = plain("example.com");
+            let typed = String::from("typed");
             assert_eq!(
-                contract.register_cookie(
-                    String::from("profile1"),
-                    String::from("cookie1"),
-                    String::from("2024-12-31"),
-                    String::from("name1"),
-                    String::from("secure1"),
-                    String::from("/path1"),
-                    String::from("value1"),
-                ),
+                contract.register_typed_cookie(profile.clone(), typed, domain, metadata, envelope),
                 Ok(())
             );
-            assert_eq!(contract.get_cookie_count(), 1);
+
+            // Host-only cookies match their exact host only
+            let cookies_for = |contract: &CookieContract, host| {
+                let chain = host_hash_chain(host);
+                let page = contract.get_cookies_for_host(String::from("profile1"), chain, None, 10);
+                page.unwrap().items.into_iter().map(|entry| entry.cookie).collect::<Vec<_>>()
+            };
+            assert_eq!(host_hash_chain("www.example.com").len(), 3);
+            assert_eq!(cookies_for(&contract, "www.example.com"), ["www", "parent"]);
+            assert_eq!(cookies_for(&contract, "example.com"), ["parent", "typed"]);
+            assert!(cookies_for(&contract, "example.org").is_empty());
+            let chain = host_hash_chain("a.b.c.d.e.f.g.h.i.j.k.l.m.n.o.p.q");
+            let lookup = contract.get_cookies_for_host(profile.clone(), chain, None, 10);
+            assert_eq!(lookup, Err(Error::InvalidLimit));
+
+            // Pages resume at the domain and position the previous one stopped at
+            let chain = host_hash_chain("www.example.com");
+            let page = contract.get_cookies_for_host(profile.clone(), chain.clone(), None, 1);
+            let page = page.unwrap();
+            assert_eq!(page.items[0].cookie, "www");
+            assert_eq!(page.next, Some((0, 1)));
+            let page = contract.get_cookies_for_host(profile.clone(), chain.clone(), page.next, 5);
+            let page = page.unwrap();
+            assert_eq!(page.items[0].cookie, "parent");
+            assert_eq!(page.next, None);
+            let lookup = contract.get_cookies_for_host(profile, chain, Some((3, 0)), 5);
+            assert_eq!(lookup, Err(Error::InvalidRange));
+
+            // The index follows domain changes and deletions
+            let patch = CookiePatch {
+                domain: Some(plain("example.com")),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("other"), patch), Ok(()));
+            assert_eq!(contract.delete_cookie(String::from("www")), Ok(()));
+            assert_eq!(cookies_for(&contract, "www.example.com"), ["parent", "other"]);
+
+            // Blinded entries are indexed by their tag only and never listed by domain
+            let tag: DomainHash = [9; 32];
+            let blinded = CookieInput {
+                domain: DomainRef::Blinded(tag),
+                ..cookie_input("blinded", "value1")
+            };
+            assert!(contract.register_cookies(vec![blinded]).is_ok());
+            let chain = vec![[8; 32], tag];
+            let page = contract.get_cookies_for_host(String::from("profile1"), chain, None, 10);
+            let entries = page.unwrap().items;
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].domain, DomainRef::Blinded(tag));
+            let alice = ink::env::caller::<ink::env::DefaultEnvironment>();
+            let listed = contract.get_domain_cookies(alice, String::from("example.com"), 0, 10);
+            assert_eq!(listed.items, ["typed", "other"]);
         }
 
         #[ink::test]
-        fn update_cookie_works() {
+        fn transactions_are_logged() {
+            let accounts = ink::env::test::default_accounts::<ink::env::DefaultEnvironment>();
             let mut contract = CookieContract::new();
-            let _ = contract.register_cookie(
-                String::from("profile1"),
-                String::from("cookie1"),
-                String::from("2024-12-31"),
-                String::from("name1"),
-                String::from("secure1"),
-                String::from("/path1"),
-                String::from("value1"),
-            );
-            assert_eq!(
-                contract.update_cookie(
-                    String::from("profile2"),
-                    String::from("cookie1"),
-                    String::from("2025-12-31"),
-                    String::from("name2"),
-                    String::from("secure2"),
-                    String::from("/path2"),
-                    String::from("value2"),
-                ),
-                Ok(())
-            );
+            assert!(contract.get_transactions_since(0, 10).is_empty());
+            let inputs = vec![cookie_input("cookie1", "value1"), cookie_input("cookie2", "value1")];
+            assert!(contract.register_cookies(inputs).is_ok());
+            let patch = CookiePatch {
+                value: Some(b"value2".to_vec()),
+                ..Default::default()
+            };
+            assert_eq!(contract.patch_cookie(String::from("cookie1"), patch), Ok(()));
+            let deleted = contract.get_cookie(String::from("cookie2")).unwrap();
+            assert_eq!(contract.delete_cookie(String::from("cookie2")), Ok(()));
+
+            let records = contract.get_transactions_since(0, 10);
+            let kinds = records.iter().map(|record| record.kind).collect::<Vec<_>>();
+            assert_eq!(kinds, [TxKind::Register, TxKind::Register, TxKind::Update, TxKind::Delete]);
+            let ids = records.iter().map(|record| record.transaction_id).collect::<Vec<_>>();
+            assert_eq!(ids, [1, 2, 3, 4]);
+            assert_eq!(records[2].cookie, (accounts.alice, String::from("cookie1")));
+            assert_eq!(records[2].caller, accounts.alice);
+            assert_eq!(contract.get_transaction(3).as_ref(), Some(&records[2]));
+
+            // Content hashes cover the entry as written, or as it was when deleted
+            let content_hash = |entry: &CookieEntry| {
+                let mut hash = [0; 32];
+                ink::env::hash_encoded::<ink::env::hash::Blake2x256, _>(entry, &mut hash);
+                hash
+            };
+            let updated = contract.get_cookie(String::from("cookie1")).unwrap();
+            assert_eq!(records[2].content_hash, content_hash(&updated));
+            assert_eq!(records[3].content_hash, content_hash(&deleted));
+
+            // Clients resume from the last id they saw
+            assert_eq!(contract.get_transactions_since(2, 1), records[2..3]);
+            assert!(contract.get_transactions_since(4, 10).is_empty());
+            assert_eq!(contract.get_transaction(5), None);
         }
 
         // #[ink::test]
@@ -327,4 +4922,102 @@ mod cookie_contract {
         //     assert_eq!(contract.get_last_stage(), Some(String::from("profile_stage")));
         // }
     }
+
+    #[cfg(all(test, feature = "e2e-tests"))]
+    mod e2e_tests {
+        use super::*;
+        use ink_e2e::build_message;
+
+        type E2EResult<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+        #[ink_e2e::test(additional_contracts = "fixtures/cookie-v1/Cargo.toml")]
+        async fn upgrade_migrates_v1_storage(
+            mut client: ink_e2e::Client<C, E>,
+        ) -> E2EResult<()> {
+            use cookie_contract_v1::CookieContractRef as CookieContractV1Ref;
+
+            // Deploy the storage version 1 contract and write a cookie
+            let constructor = CookieContractV1Ref::new();
+            let contract_id = client
+                .instantiate("cookie_contract_v1", &ink_e2e::alice(), constructor, 0, None)
+                .await
+                .expect("instantiate failed")
+                .account_id;
+            let register = build_message::<CookieContractV1Ref>(contract_id.clone()).call(|v1| {
+                v1.register_cookie(
+                    String::from("profile1"),
+                    String::from("cookie1"),
+                    b"2024-12-31".to_vec(),
+                    b"name1".to_vec(),
+                    b"secure1".to_vec(),
+                    b"/path1".to_vec(),
+                    b"value1".to_vec(),
+                )
+            });
+            client
+                .call(&ink_e2e::alice(), register, 0, None)
+                .await
+                .expect("register failed");
+
+            // Move it to the current code
+            let code_hash = client
+                .upload("cookie_contract", &ink_e2e::alice(), None)
+                .await
+                .expect("upload failed")
+                .code_hash;
+            let upgrade = build_message::<CookieContractV1Ref>(contract_id.clone())
+                .call(|contract| contract.upgrade(code_hash));
+            let result = client.call(&ink_e2e::alice(), upgrade, 0, None).await;
+            assert_eq!(result.expect("upgrade failed").return_value(), Ok(()));
+
+            // Writes wait for the owner to migrate the old storage
+            let register_cookie = |cookie: &str| {
+                let cookie = String::from(cookie);
+                build_message::<CookieContractRef>(contract_id.clone()).call(|contract| {
+                    contract.register_cookie(
+                        String::from("profile1"),
+                        cookie,
+                        DomainRef::Plain(String::from("example.com")),
+                        b"2024-12-31".to_vec(),
+                        b"name1".to_vec(),
+                        b"secure1".to_vec(),
+                        b"/path1".to_vec(),
+                        b"value1".to_vec(),
+                    )
+                })
+            };
+            let register = register_cookie("cookie2");
+            let result = client.call_dry_run(&ink_e2e::alice(), &register, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::MigrationPending));
+            let migrate = build_message::<CookieContractRef>(contract_id.clone())
+                .call(|contract| contract.migrate(10));
+            let result = client.call_dry_run(&ink_e2e::bob(), &migrate, 0, None).await;
+            assert_eq!(result.return_value(), Err(Error::MissingRole(Role::Owner)));
+            let result = client.call(&ink_e2e::alice(), migrate, 0, None).await;
+            assert_eq!(result.expect("migrate failed").return_value(), Ok(None));
+            let get_storage_version = build_message::<CookieContractRef>(contract_id.clone())
+                .call(|contract| contract.get_storage_version());
+            let version = client
+                .call_dry_run(&ink_e2e::alice(), &get_storage_version, 0, None)
+                .await
+                .return_value();
+            assert_eq!(version, STORAGE_VERSION);
+
+            // The cookie reads back with the fields added since
+            let get_cookie = build_message::<CookieContractRef>(contract_id.clone())
+                .call(|contract| contract.get_cookie(String::from("cookie1")));
+            let entry = client
+                .call_dry_run(&ink_e2e::alice(), &get_cookie, 0, None)
+                .await
+                .return_value()
+                .expect("cookie lost in upgrade");
+            assert_eq!(entry.value, b"value1");
+            assert_eq!(entry.domain, DomainRef::Plain(String::from("cookie1")));
+            assert_eq!((entry.expires_at, entry.deposit), (None, 0));
+            let result = client.call(&ink_e2e::alice(), register_cookie("cookie2"), 0, None).await;
+            assert_eq!(result.expect("register failed").return_value(), Ok(()));
+
+            Ok(())
+        }
+    }
 }